@@ -0,0 +1,56 @@
+// feeds byte streams into a headless State and reports the throughput
+// cargo bench --features t --bench throughput [-- recorded_stream...]
+// a stream can be recorded with e.g. `script -q -c 'cat big.log' /tmp/stream`
+use sea_tf::t::State;
+use std::io::Write;
+use std::time::Instant;
+
+const MIN_BYTES: usize = 64 << 20;   // each stream is repeated until at least this much was written
+const CHUNK: usize = 4096;           // roughly what one read from the pty returns
+
+fn main() {
+    let files: Vec<String> = std::env::args().skip(1).filter(|a| !a.starts_with("--")).collect();
+    let streams = if files.is_empty() {
+	builtin()
+    } else {
+	files.into_iter().map(|f| {
+	    let bytes = std::fs::read(&f).unwrap_or_else(|e| panic!("cannot read {}: {}", f, e));
+	    (f, bytes)
+	}).collect()
+    };
+    for (name, bytes) in streams {
+	if bytes.is_empty() {continue};
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	let mut total = 0;
+	let start = Instant::now();
+	while total < MIN_BYTES {
+	    for chunk in bytes.chunks(CHUNK) {
+		state.write_all(chunk).unwrap();
+	    }
+	    total += bytes.len();
+	    state.take_reply();
+	}
+	let secs = start.elapsed().as_secs_f64();
+	println!("{:<24} {:>10.1} MB/s", name, total as f64 / secs / 1e6);
+    }
+}
+
+// streams resembling typical output if no recordings are given
+fn builtin() -> Vec<(String, Vec<u8>)> {
+    let mut log = Vec::new();
+    let mut colour = Vec::new();
+    let mut unicode = Vec::new();
+    let mut cursor = Vec::new();
+    for i in 0..20000 {
+	writeln!(log, "2024-01-01 12:00:{:02} INFO request {} handled in {} ms", i%60, i, i%997).unwrap();
+	writeln!(colour, "\x1b[1;34mdrwxr-xr-x\x1b[0m  user \x1b[32m{:>8}\x1b[0m \x1b[45mfile_{}.rs\x1b[m", i*31, i).unwrap();
+	writeln!(unicode, "Grüße aus Köln – {} × {} = {} ✓", i, i, i*i).unwrap();
+	write!(cursor, "\x1b[{};{}H{}\x1b[K\x1b[2A\x1b[5C\r", i%24+1, i%50+1, i).unwrap();
+    }
+    vec![
+	(String::from("ascii log"), log),
+	(String::from("sgr colours"), colour),
+	(String::from("utf-8 text"), unicode),
+	(String::from("cursor movement"), cursor),
+    ]
+}
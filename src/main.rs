@@ -1,44 +1,180 @@
 use sea_tf::t;
-use std::{io::Write,io::Read,time::Duration,thread};
+use std::{io::Write,io::Read,time::Instant};
+use std::collections::HashSet;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut state: t::State<'_,Box<dyn FnMut(Option<usize>)>> = t::State::new();
+    let mut config = t::state::Config::new();
+    config.trace = t::TraceTarget::from_env();   // --trace takes precedence
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+	match &arg[..] {
+	    "--trace" => config.trace = Some(t::TraceTarget::parse(&args.next().unwrap_or(String::from("-")))),
+	    "--print-terminfo" => {
+		print!("{}", t::caps::terminfo());
+		return Ok(());
+	    },
+	    _ => return Err(format!("unknown argument {}, usage: sea_tf [--trace FILE|-] [--print-terminfo]", arg).into()),
+	}
+    }
+    let mut state: t::State<'_,Box<dyn FnMut(Option<usize>)>> = t::State::with_config(config);
+    state.open_trace()?;
     //let _ = state.write_all(b"Hello World!\n");
     let mut window = t::Window::new(&state)?;
-    let mut pty = t::Pty::new(&state.config)?;
+    let (c_w, c_h) = window.cell_size();
+    state.set_cell_size(c_w, c_h);
+    let (s_w, s_h) = window.screen_size();
+    state.set_screen_size(s_w, s_h);
+    let mut pty = t::Pty::new(&state.config, state.winsize())?;
 
     
-    let dt = Duration::from_millis(100);
-    let mut timer = 10;
     let mut read_buf = [0;512];
+    let mut pressed = HashSet::new();
+    let mut consumed = HashSet::new();   // keys whose press the client did not see, nor will it their release
 
     loop {
-	let (new_timer, ev) = Event::wait_for_event(timer, dt, &mut window.conn, &pty.fd)?;
-	timer = new_timer;
+	pty.resize(state.winsize())?;   // after CSI t or CSI n;73~
+	let deadline = [state.sync_deadline(), window.blink_deadline(&state)].into_iter().flatten().min();
+	let ev = Event::wait_for_event(&window.conn, &pty, deadline)?;
 	match ev {
+	    Event::X(xcb::Event::X(xcb::x::Event::FocusOut(ev))) if ev.mode() == xcb::x::NotifyMode::Grab => {
+		window.set_focus(false, &mut state);  // e.g. a window manager shortcut, we get focus back
+		window.conn.flush()?;
+	    },
 	    Event::X(xcb::Event::X(xcb::x::Event::FocusOut(_ev))) => {
 		window.conn.send_request(&xcb::x::FreeGc {gc: window.gc});
 		break;
 	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::FocusIn(_ev))) => {
+		window.set_focus(true, &mut state);
+		window.conn.flush()?;
+	    },
 	    Event::X(xcb::Event::X(xcb::x::Event::KeyPress(ev))) => {
 		window.xkb_state.update_key(ev.detail().into(),xkbcommon::xkb::KeyDirection::Down);
 		let key_sym = window.get_keysym(&ev);
 		let key_sym_mod = window.get_keysym_mod(&ev);
 		//println!("key down: {}",xkbcommon::xkb::keysym_get_name(key_sym));
-		let tmp = t::Pty::parse_key(key_sym,ev.state(),key_sym_mod);
+		let key_ev = if pressed.insert(ev.detail()) {t::KeyEvent::Press} else {t::KeyEvent::Repeat};
+		if state.copying() {   // vi keys until y, q or Escape
+		    if let Some(key) = t::copy::CopyKey::from_key(key_sym, ev.state(), key_sym_mod) {
+			let mut copied = None;
+			state.do_and_redraw(|st| copied = st.copy_key(key), &mut window);
+			if let Some((clip, text)) = copied {window.set_selection(clip, text)};
+			window.conn.flush()?;
+		    }
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		if t::copy::CopyKey::starts(key_sym, ev.state()) {
+		    state.do_and_redraw(|st| st.start_copy(), &mut window);
+		    window.conn.flush()?;
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		if state.searching() {   // keys edit the pattern until Escape
+		    if let Some(key) = t::search::SearchKey::from_key(key_sym, ev.state(), key_sym_mod) {
+			state.do_and_redraw(|st| st.search_key(key), &mut window);
+			window.conn.flush()?;
+		    }
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		if t::search::SearchKey::starts(key_sym, ev.state()) {
+		    state.do_and_redraw(|st| st.start_search(), &mut window);
+		    window.conn.flush()?;
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		if let Some(clip) = t::select::Clip::from_key(key_sym, ev.state()) {
+		    window.request_paste(clip, ev.time());
+		    window.conn.flush()?;
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		if let Some(n) = window.scroll_key(key_sym, ev.state(), &state) {
+		    state.do_and_redraw(|st| st.scroll_view(n), &mut window);
+		    window.conn.flush()?;
+		    consumed.insert(ev.detail());
+		    continue;
+		}
+		let tmp = t::Pty::encode_key(state.keyboard_flags(),key_sym,ev.state(),key_sym_mod,key_ev);
 		if tmp.len() != 0 {
-		    let _ = pty.fd.write_all(&tmp.as_bytes());
-		    timer = 10;
+		    let _ = pty.write(tmp.as_bytes());
+		    window.reset_blink(&state.config);
 		    //println!("sent: {}",tmp);
 		}
 	    },
 	    Event::X(xcb::Event::X(xcb::x::Event::KeyRelease(ev))) => {
+		pressed.remove(&ev.detail());
+		let key_sym = window.get_keysym(&ev);
+		let key_sym_mod = window.get_keysym_mod(&ev);
 		window.xkb_state.update_key(ev.detail().into(),xkbcommon::xkb::KeyDirection::Up);
+		if consumed.remove(&ev.detail()) {continue};
+		let tmp = t::Pty::encode_key(state.keyboard_flags(),key_sym,ev.state(),key_sym_mod,t::KeyEvent::Release);
+		if tmp.len() != 0 {
+		    let _ = pty.write(tmp.as_bytes());
+		}
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::ButtonPress(ev))) => {
+		match ev.detail() {
+		    1 => {
+			let ((row, col), clicks) = window.click(&ev);
+			let block = ev.state().contains(xcb::x::KeyButMask::MOD1);   // Alt-drag selects a block
+			state.do_and_redraw(|st| st.mouse_press(row, col, clicks, block), &mut window);
+		    },
+		    2 => window.request_paste(t::select::Clip::Primary, ev.time()),
+		    button => if let Some(n) = window.scroll_button(button) {
+			state.do_and_redraw(|st| st.scroll_view(n), &mut window);
+		    },
+		}
+		window.conn.flush()?;
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::MotionNotify(ev))) => {
+		let (row, col) = window.cell_at(ev.event_x(), ev.event_y());
+		state.do_and_redraw(|st| st.mouse_drag(row, col), &mut window);
+		window.conn.flush()?;
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::ButtonRelease(ev))) if ev.detail() == 1 => {
+		if let Some(text) = state.mouse_release() {
+		    window.set_selection(t::select::Clip::Primary, text);
+		    window.conn.flush()?;
+		}
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::SelectionNotify(ev))) => {
+		if let Some(text) = window.paste_notify(&ev) {
+		    pty.write(&state.paste(&text))?;   // queued, the client may take it slowly
+		}
+		window.conn.flush()?;
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::PropertyNotify(ev))) => {
+		if let Some(text) = window.paste_property(&ev) {
+		    pty.write(&state.paste(&text))?;   // queued, the client may take it slowly
+		}
+		window.conn.flush()?;
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::SelectionRequest(ev))) => {
+		window.selection_request(&ev);
+		window.conn.flush()?;
+	    },
+	    Event::X(xcb::Event::X(xcb::x::Event::SelectionClear(ev))) => {
+		if window.selection_clear(&ev) {
+		    state.do_and_redraw(|st| st.clear_selection(), &mut window);
+		    window.conn.flush()?;
+		}
 	    },
 	    Event::T() => {
-		let n = pty.fd.read(&mut read_buf)?;
+		let n = match pty.fd.read(&mut read_buf) {
+		    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+		    res => res?,
+		};
 		state.do_and_redraw(|st| {let _ = st.write_all(&read_buf[0..n]);}, &mut window);
 		window.conn.flush()?;
+		pty.write(&state.take_reply())?;
+	    },
+	    Event::Writable() => pty.send_pending()?,
+	    Event::Timeout() => {
+		window.blink(&mut state);
+		state.do_and_redraw(|_| (), &mut window);  // synchronized update took too long, draw what we have
+		window.conn.flush()?;
 	    },
 	    _ => (),
 	}
@@ -49,29 +185,41 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 enum Event {
     X(xcb::Event),
     T(),
+    Writable(),   // the pty takes more of the pending input
+    Timeout(),
 }
 impl Event {
-    fn read_ready(file: &std::fs::File) -> bool {
+    // blocks in one poll() on the X connection and the pty until either has something or the deadline passes,
+    // the pty is also polled for writing while it has pending input
+    pub fn wait_for_event(conn: &xcb::Connection, pty: &t::Pty, deadline: Option<Instant>)
+			  -> Result<Event, Box<dyn std::error::Error>> {
 	use std::os::unix::io::AsRawFd;
-	let mut set = nix::sys::select::FdSet::new();
-	set.insert(file.as_raw_fd());
-	let mut timeout = nix::sys::time::TimeVal::new(0,100);
-	nix::sys::select::select(None,&mut set,None,None,&mut timeout) != Ok(0)
-    }
-    // (timer, event) = wait_for_event(timer, dt, conn, file)?;
-    pub fn wait_for_event(timer: u32, dt: Duration, conn: &xcb::Connection, pty: &std::fs::File)
-			  -> Result<(u32, Event), Box<dyn std::error::Error>> {
-	
-	for time in 0..timer {
-	    if Self::read_ready(pty) {
-		let t = if time == 0 {timer} else {timer+1-time};
-		return Ok((t, Self::T()));
+	use nix::poll::{poll,PollFd,PollFlags};
+	loop {
+	    if let Some(event) = conn.poll_for_event()? {   // also those already read from the socket
+		return Ok(Self::X(event));
+	    }
+	    let timeout = match deadline {
+		None => -1,
+		Some(d) => {
+		    let now = Instant::now();
+		    if now >= d {return Ok(Self::Timeout())};
+		    i32::try_from((d-now).as_micros().div_ceil(1000)).unwrap_or(i32::MAX)   // not early
+		},
+	    };
+	    conn.flush()?;
+	    let pty_flags = if pty.writing() {PollFlags::POLLIN | PollFlags::POLLOUT} else {PollFlags::POLLIN};
+	    let mut fds = [PollFd::new(conn.as_raw_fd(), PollFlags::POLLIN), PollFd::new(pty.fd.as_raw_fd(), pty_flags)];
+	    match poll(&mut fds, timeout) {
+		Err(nix::errno::Errno::EINTR) => continue,
+		res => res?,
+	    };
+	    if fds[1].revents().is_some_and(|r| r.intersects(PollFlags::POLLIN | PollFlags::POLLHUP)) {
+		return Ok(Self::T());   // a hang up is read as an error
 	    }
-	    if let Some(event) = conn.poll_for_event()? {
-		return Ok((timer-time, Self::X(event)));
+	    if fds[1].revents().is_some_and(|r| r.contains(PollFlags::POLLOUT)) {
+		return Ok(Self::Writable());
 	    }
-	    thread::sleep(dt);
 	}
-	Ok((0, Event::X(conn.wait_for_event()?)))
     }
 }
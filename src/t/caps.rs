@@ -0,0 +1,205 @@
+// what SeaTF understands and sends, in terminfo notation, answered to XTGETTCAP
+use std::env;
+use std::path::{Path,PathBuf};
+
+pub const NAME: &str = "sea-tf";
+
+pub enum Cap {
+    Bool,
+    Num(u16),
+    Str(&'static str),
+}
+pub static CAPS: &[(&str, Cap)] = &[
+    ("am", Cap::Bool),
+    ("xenl", Cap::Bool),
+    ("cols", Cap::Num(50)),
+    ("it", Cap::Num(8)),
+    ("lines", Cap::Num(24)),
+    ("bel", Cap::Str("^G")),
+    ("civis", Cap::Str("\\E[?25l")),
+    ("clear", Cap::Str("\\E[H\\E[2J")),
+    ("cnorm", Cap::Str("\\E[?12l\\E[?25h")),
+    ("cr", Cap::Str("\\r")),
+    ("cub", Cap::Str("\\E[%p1%dD")),
+    ("cub1", Cap::Str("^H")),
+    ("cud", Cap::Str("\\E[%p1%dB")),
+    ("cud1", Cap::Str("^J")),
+    ("cuf", Cap::Str("\\E[%p1%dC")),
+    ("cuf1", Cap::Str("\\E[C")),
+    ("cup", Cap::Str("\\E[%i%p1%d;%p2%dH")),
+    ("cuu", Cap::Str("\\E[%p1%dA")),
+    ("cuu1", Cap::Str("\\E[A")),
+    ("cvvis", Cap::Str("\\E[?12;25h")),
+    ("dch", Cap::Str("\\E[%p1%dP")),
+    ("dch1", Cap::Str("\\E[P")),
+    ("dl", Cap::Str("\\E[%p1%dM")),
+    ("dl1", Cap::Str("\\E[M")),
+    ("ed", Cap::Str("\\E[J")),
+    ("el", Cap::Str("\\E[K")),
+    ("el1", Cap::Str("\\E[1K")),
+    ("flash", Cap::Str("\\E[?5h$<100/>\\E[?5l")),
+    ("home", Cap::Str("\\E[H")),
+    ("hpa", Cap::Str("\\E[%i%p1%dG")),
+    ("ht", Cap::Str("^I")),
+    ("ich", Cap::Str("\\E[%p1%d@")),
+    ("il", Cap::Str("\\E[%p1%dL")),
+    ("il1", Cap::Str("\\E[L")),
+    ("ind", Cap::Str("^J")),
+    ("indn", Cap::Str("\\E[%p1%dS")),
+    ("kbs", Cap::Str("^?")),
+    ("kcbt", Cap::Str("\\E[Z")),
+    ("kcub1", Cap::Str("\\E[D")),
+    ("kcud1", Cap::Str("\\E[B")),
+    ("kcuf1", Cap::Str("\\E[C")),
+    ("kcuu1", Cap::Str("\\E[A")),
+    ("kdch1", Cap::Str("\\E[P")),
+    ("kend", Cap::Str("\\E[4~")),
+    ("khome", Cap::Str("\\E[H")),
+    ("kich1", Cap::Str("\\E[4h")),
+    ("knp", Cap::Str("\\E[6~")),
+    ("kpp", Cap::Str("\\E[5~")),
+    ("nel", Cap::Str("\\r^J")),
+    ("oc", Cap::Str("\\E]104\\E\\\\")),
+    ("rin", Cap::Str("\\E[%p1%dT")),
+    ("rs1", Cap::Str("\\Ec")),
+    ("rs2", Cap::Str("\\E[!p")),
+    ("sgr0", Cap::Str("\\E[m")),
+    ("u6", Cap::Str("\\E[%i%d;%dR")),
+    ("u7", Cap::Str("\\E[6n")),
+    ("u8", Cap::Str("\\E[?%[;0123456789]c")),
+    ("u9", Cap::Str("\\E[c")),
+    ("BD", Cap::Str("\\E[?2004l")),
+    ("BE", Cap::Str("\\E[?2004h")),
+    ("E3", Cap::Str("\\E[3J")),
+    ("PE", Cap::Str("\\E[201~")),
+    ("PS", Cap::Str("\\E[200~")),
+    ("Se", Cap::Str("\\E[0 q")),
+    ("Ss", Cap::Str("\\E[%p1%d q")),
+    ("Sync", Cap::Str("\\E[?2026%?%p1%{1}%-%tl%eh%;")),
+];
+
+pub fn get(name: &str) -> Option<&'static Cap> {
+    CAPS.iter().find(|(n, _)| *n == name).map(|(_, cap)| cap)
+}
+// bytes of a terminfo string value
+pub fn unescape(value: &str) -> Vec<u8> {
+    let mut res = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(b) = bytes.next() {
+	match b {
+	    b'\\' => match bytes.next() {
+		Some(b'E' | b'e') => res.push(0x1b),
+		Some(b'n') => res.push(b'\n'),
+		Some(b'r') => res.push(b'\r'),
+		Some(b't') => res.push(b'\t'),
+		Some(b'b') => res.push(0x08),
+		Some(b'f') => res.push(0x0c),
+		Some(b's') => res.push(b' '),
+		Some(b) => res.push(b),
+		None => res.push(b'\\'),
+	    },
+	    b'^' => match bytes.next() {
+		Some(b'?') => res.push(0x7f),
+		Some(b) => res.push(b & 0x1f),
+		None => res.push(b'^'),
+	    },
+	    _ => res.push(b),
+	}
+    }
+    res
+}
+// reply to XTGETTCAP for one hex encoded name, TN is the terminal name
+pub fn xtgettcap(hex_name: &[u8]) -> Vec<u8> {
+    let value = match hex_decode(hex_name).as_deref() {
+	Some("TN") => Some(Some(NAME.as_bytes().to_vec())),
+	Some(name) => get(name).map(|cap| match cap {
+	    Cap::Bool => None,
+	    Cap::Num(n) => Some(n.to_string().into_bytes()),
+	    Cap::Str(s) => Some(unescape(s)),
+	}),
+	None => None,
+    };
+    let mut reply = Vec::new();
+    reply.extend_from_slice(if value.is_some() {b"\x1bP1+r"} else {b"\x1bP0+r"});
+    reply.extend_from_slice(hex_name);
+    if let Some(Some(value)) = value {
+	reply.push(b'=');
+	for b in value {
+	    reply.extend_from_slice(format!("{:02X}", b).as_bytes());
+	}
+    }
+    reply.extend_from_slice(b"\x1b\\");
+    reply
+}
+// source for tic, the capitalized names are extensions that need tic -x
+pub fn terminfo() -> String {
+    let mut src = format!("{}|SeaTF drop-down terminal emulator,\n", NAME);
+    for (name, cap) in CAPS {
+	src += &match cap {
+	    Cap::Bool => format!("\t{},\n", name),
+	    Cap::Num(n) => format!("\t{}#{},\n", name, n),
+	    Cap::Str(s) => format!("\t{}={},\n", name, s),
+	};
+    }
+    src
+}
+// searched like ncurses does
+pub fn installed(name: &str) -> bool {
+    let mut dirs = Vec::new();
+    if let Some(dir) = env::var_os("TERMINFO") {dirs.push(PathBuf::from(dir))};
+    if let Some(home) = env::var_os("HOME") {dirs.push(Path::new(&home).join(".terminfo"))};
+    let system = ["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo", "/usr/lib/terminfo"];
+    match env::var_os("TERMINFO_DIRS") {
+	Some(list) => for dir in env::split_paths(&list) {   // an empty entry stands for the system ones
+	    if dir.as_os_str().is_empty() {
+		dirs.extend(system.iter().map(PathBuf::from));
+	    } else {
+		dirs.push(dir);
+	    }
+	},
+	None => dirs.extend(system.iter().map(PathBuf::from)),
+    }
+    dirs.iter().any(|dir| installed_in(dir, name))
+}
+// in a directory named after the first letter or its hex code
+fn installed_in(dir: &Path, name: &str) -> bool {
+    let Some(first) = name.bytes().next() else {return false};
+    dir.join(&name[..1]).join(name).is_file() || dir.join(format!("{:02x}", first)).join(name).is_file()
+}
+fn hex_decode(hex: &[u8]) -> Option<String> {
+    if hex.len()%2 != 0 {return None};
+    let bytes = hex.chunks(2)
+	.map(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok())
+	.collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod caps_tests {
+    use super::*;
+    #[test]
+    fn values() {
+	assert_eq!(unescape("\\E[?12l\\E[?25h"), b"\x1b[?12l\x1b[?25h");
+	assert_eq!(unescape("^G^?\\r\\\\\\,"), b"\x07\x7f\r\\,");
+	assert_eq!(xtgettcap(b"544E"), b"\x1bP1+r544E=7365612D7466\x1b\\");
+	assert_eq!(xtgettcap(b"636F6C73"), b"\x1bP1+r636F6C73=3530\x1b\\");
+	assert_eq!(xtgettcap(b"616D"), b"\x1bP1+r616D\x1b\\");
+	assert_eq!(xtgettcap(b"636363"), b"\x1bP0+r636363\x1b\\");   // no palette for text
+	assert_eq!(xtgettcap(b"6b63757531"), b"\x1bP1+r6b63757531=1B5B41\x1b\\");
+	assert_eq!(xtgettcap(b"5463"), b"\x1bP0+r5463\x1b\\");
+	assert_eq!(xtgettcap(b"zz"), b"\x1bP0+rzz\x1b\\");
+    }
+    #[test]
+    fn source() {
+	let src = terminfo();
+	assert!(src.starts_with("sea-tf|SeaTF drop-down terminal emulator,\n\tam,\n\txenl,\n\tcols#50,\n"));
+	assert!(src.contains("\n\tcup=\\E[%i%p1%d;%p2%dH,\n"));
+	assert_eq!(src.lines().count(), CAPS.len()+1);
+	let dir = env::temp_dir().join(format!("sea_tf_terminfo_{}", std::process::id()));
+	std::fs::create_dir_all(dir.join("73")).unwrap();
+	std::fs::write(dir.join("73").join(NAME), b"").unwrap();
+	assert!(installed_in(&dir, NAME));
+	assert!(!installed_in(&dir, "sea-tf-missing"));
+	std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+// colors as 0xrrggbb
+pub type Rgb = u32;
+
+// xterm's default for the 16 ANSI colors
+pub const ANSI_COLORS: [Rgb; 16] = [
+    0x000000, 0xcd0000, 0x00cd00, 0xcdcd00, 0x0000ee, 0xcd00cd, 0x00cdcd, 0xe5e5e5,
+    0x7f7f7f, 0xff0000, 0x00ff00, 0xffff00, 0x5c5cff, 0xff00ff, 0x00ffff, 0xffffff,
+];
+
+// colors changed by the client with OSC 4/10/11/12, None: as in Config
+// the palette is only used for sixel images, text is drawn in the foreground color
+pub(super) struct Colors {
+    pub palette: [Option<Rgb>; 256],
+    pub fg: Option<Rgb>,
+    pub bg: Option<Rgb>,
+    pub cursor: Option<Rgb>,
+}
+impl Colors {
+    pub fn new() -> Colors {
+	Colors {
+	    palette: [None; 256],
+	    fg: None,
+	    bg: None,
+	    cursor: None,
+	}
+    }
+}
+
+// 0-15 from ansi, 16-231 the 6x6x6 color cube, 232-255 gray ramp
+pub fn palette_default(ansi: &[Rgb; 16], i: u8) -> Rgb {
+    match i {
+	0..=15 => ansi[i as usize],
+	16..=231 => {
+	    let level = |c: u8| if c == 0 {0} else {55+40*c as Rgb};
+	    let c = i-16;
+	    level(c/36) << 16 | level(c/6%6) << 8 | level(c%6)
+	},
+	_ => {
+	    let gray = 8+10*(i-232) as Rgb;
+	    gray << 16 | gray << 8 | gray
+	},
+    }
+}
+
+// X color specifications "rgb:r/g/b" with 1-4 hex digits per component or "#rgb" with 1-4 digits
+pub fn parse(spec: &[u8]) -> Option<Rgb> {
+    let spec = std::str::from_utf8(spec).ok()?;
+    let parts: Vec<&str> = if let Some(rgb) = spec.strip_prefix("rgb:") {
+	rgb.split('/').collect()
+    } else if let Some(hex) = spec.strip_prefix('#') {
+	let n = hex.len()/3;
+	if n == 0 || hex.len()%3 != 0 || !hex.is_ascii() {return None};
+	(0..3).map(|i| &hex[i*n..(i+1)*n]).collect()
+    } else {
+	return None;
+    };
+    if parts.len() != 3 {return None};
+    let mut rgb = 0;
+    for part in parts {
+	if part.is_empty() || part.len() > 4 {return None};
+	let v = u32::from_str_radix(part, 16).ok()?;
+	let v = if spec.starts_with('#') {   // most significant bits
+	    if part.len() <= 2 {v << (8-4*part.len())} else {v >> (4*part.len()-8)}
+	} else {   // scaled
+	    v*255 / ((1 << (4*part.len()))-1)
+	};
+	rgb = rgb << 8 | v;
+    }
+    Some(rgb)
+}
+// as reported to clients
+pub fn format(rgb: Rgb) -> String {
+    let (r, g, b) = (rgb >> 16 & 0xff, rgb >> 8 & 0xff, rgb & 0xff);
+    format!("rgb:{:02x}{:02x}/{:02x}{:02x}/{:02x}{:02x}", r, r, g, g, b, b)
+}
+
+#[cfg(test)]
+mod color_tests {
+    use super::*;
+    #[test]
+    fn specs() {
+	assert_eq!(parse(b"rgb:ff/80/0"), Some(0xff8000));
+	assert_eq!(parse(b"rgb:ffff/8080/1"), Some(0xff8011));
+	assert_eq!(parse(b"#f80"), Some(0xf08000));
+	assert_eq!(parse(b"#102030"), Some(0x102030));
+	assert_eq!(parse(b"#fff000ccc"), Some(0xff00cc));
+	assert_eq!(parse(b"rgb:1/2"), None);
+	assert_eq!(parse(b"#12345"), None);
+	assert_eq!(parse(b"red"), None);
+	assert_eq!(format(0x10ff00), "rgb:1010/ffff/0000");
+    }
+    #[test]
+    fn palette() {
+	assert_eq!(palette_default(&ANSI_COLORS, 1), 0xcd0000);
+	assert_eq!(palette_default(&ANSI_COLORS, 196), 0xff0000);
+	assert_eq!(palette_default(&ANSI_COLORS, 67), 0x5f87af);
+	assert_eq!(palette_default(&ANSI_COLORS, 255), 0xeeeeee);
+    }
+}
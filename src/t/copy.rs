@@ -0,0 +1,307 @@
+// vi-like copy mode, the view stays on the lines it shows while output goes on
+use std::cmp;
+use xcb::x::KeyButMask;
+use xkbcommon::xkb;
+use super::State;
+use super::select::{Selection,SelectKind,Clip};
+use super::write::{IMAGE_CELL,WIDE_CELL};
+
+pub struct CopyMode {
+    cursor: (usize, usize),   // history line and column
+    clip: Clip,   // where y copies to, "* and "+ choose
+    quote: bool,   // " typed, a register follows
+}
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CopyKey {
+    Left,
+    Right,
+    Up,
+    Down,
+    LineStart,
+    FirstChar,
+    LineEnd,
+    WordNext,
+    WordEnd,
+    WordPrev,
+    Top,
+    Bottom,
+    HalfPageUp,
+    HalfPageDown,
+    PageUp,
+    PageDown,
+    Select(SelectKind),
+    Quote,
+    Register(char),
+    Yank,
+    Escape,
+    Quit,
+}
+impl CopyKey {
+    // Ctrl+Shift+Space starts copy mode
+    pub fn starts(key_sym: xkb::Keysym, mask: KeyButMask) -> bool {
+	mask & MODS == KeyButMask::SHIFT | KeyButMask::CONTROL && xkb::keysym_get_name(key_sym) == "space"
+    }
+    pub fn from_key(key_sym: xkb::Keysym, mask: KeyButMask, key_sym_mod: xkb::Keysym) -> Option<CopyKey> {
+	if mask & MODS == KeyButMask::CONTROL {
+	    return match &xkb::keysym_get_name(key_sym)[..] {
+		"v" => Some(CopyKey::Select(SelectKind::Block)),
+		"u" => Some(CopyKey::HalfPageUp),
+		"d" => Some(CopyKey::HalfPageDown),
+		"b" => Some(CopyKey::PageUp),
+		"f" => Some(CopyKey::PageDown),
+		"c" => Some(CopyKey::Quit),
+		_ => None,
+	    };
+	}
+	match &xkb::keysym_get_name(key_sym)[..] {
+	    "Escape" => return Some(CopyKey::Escape),
+	    "Left" => return Some(CopyKey::Left),
+	    "Right" => return Some(CopyKey::Right),
+	    "Up" => return Some(CopyKey::Up),
+	    "Down" => return Some(CopyKey::Down),
+	    "Home" => return Some(CopyKey::LineStart),
+	    "End" => return Some(CopyKey::LineEnd),
+	    "Prior" => return Some(CopyKey::PageUp),
+	    "Next" => return Some(CopyKey::PageDown),
+	    _ => (),
+	}
+	match xkb::keysym_to_utf8(key_sym_mod).chars().next()? {
+	    'h' => Some(CopyKey::Left),
+	    'l' => Some(CopyKey::Right),
+	    'k' => Some(CopyKey::Up),
+	    'j' => Some(CopyKey::Down),
+	    '0' => Some(CopyKey::LineStart),
+	    '^' => Some(CopyKey::FirstChar),
+	    '$' => Some(CopyKey::LineEnd),
+	    'w' => Some(CopyKey::WordNext),
+	    'e' => Some(CopyKey::WordEnd),
+	    'b' => Some(CopyKey::WordPrev),
+	    'g' => Some(CopyKey::Top),
+	    'G' => Some(CopyKey::Bottom),
+	    'v' => Some(CopyKey::Select(SelectKind::Char)),
+	    'V' => Some(CopyKey::Select(SelectKind::Line)),
+	    '"' => Some(CopyKey::Quote),
+	    c @ ('*' | '+') => Some(CopyKey::Register(c)),
+	    'y' => Some(CopyKey::Yank),
+	    'q' => Some(CopyKey::Quit),
+	    _ => None,
+	}
+    }
+}
+const MODS: KeyButMask = KeyButMask::from_bits_truncate(
+    KeyButMask::SHIFT.bits() | KeyButMask::CONTROL.bits() | KeyButMask::MOD1.bits());
+
+// vi word classes: blank, keyword and other
+fn class(c: u8) -> u8 {
+    match c {
+	b' ' | IMAGE_CELL => 0,
+	b'_' | b'?' | WIDE_CELL => 1,
+	c if c.is_ascii_alphanumeric() => 1,
+	_ => 2,
+    }
+}
+// start of the first word at or after c
+fn word_start_from(line: &[u8], mut c: usize) -> Option<usize> {
+    while c < line.len() && class(line[c]) == 0 {c += 1};
+    if c < line.len() {Some(c)} else {None}
+}
+// end of the first word at or after c
+fn word_end_from(line: &[u8], c: usize) -> Option<usize> {
+    let mut c = word_start_from(line, c)?;
+    let cls = class(line[c]);
+    while c+1 < line.len() && class(line[c+1]) == cls {c += 1};
+    Some(c)
+}
+// start of the last word before c
+fn word_start_before(line: &[u8], mut c: usize) -> Option<usize> {
+    while c > 0 && class(line[c-1]) == 0 {c -= 1};
+    if c == 0 {return None};
+    c -= 1;
+    let cls = class(line[c]);
+    while c > 0 && class(line[c-1]) == cls {c -= 1};
+    Some(c)
+}
+
+impl<T: FnMut(Option<usize>)> State<'_, T> {
+    pub fn start_copy(&mut self) {
+	let cursor = (self.scrollback.len() + self.lines.cur().saturating_sub(self.lines.start), self.col);
+	self.copy = Some(CopyMode {cursor, clip: Clip::Clipboard, quote: false});
+	self.selection = None;
+	self.copy_move(cursor);
+    }
+    pub fn copying(&self) -> bool {
+	self.copy.is_some()
+    }
+    // after output, with the scrollback length and dropped lines before it, the selection and the copy
+    // cursor stay on their lines, so does the view in copy mode
+    pub(super) fn follow_history(&mut self, (len, dropped): (usize, usize)) {
+	let dropped = self.scrollback.dropped - dropped;
+	let last = self.history_len().saturating_sub(1);
+	let shift = |at: &mut (usize, usize)| at.0 = cmp::min(at.0.saturating_sub(dropped), last);
+	if let Some(sel) = &mut self.selection {
+	    shift(&mut sel.anchor);
+	    shift(&mut sel.head);
+	}
+	let Some(copy) = &mut self.copy else {return};
+	shift(&mut copy.cursor);
+	let offset = self.scrollback.offset + self.scrollback.len() + dropped;
+	self.scrollback.offset = cmp::min(offset.saturating_sub(len), self.scrollback.len());
+    }
+    // the text to put into an X selection when y was typed
+    pub fn copy_key(&mut self, key: CopyKey) -> Option<(Clip, Vec<u8>)> {
+	let copy = self.copy.as_mut()?;
+	let (quote, clip, (i, c)) = (std::mem::replace(&mut copy.quote, false), copy.clip, copy.cursor);
+	let len = self.history_len();
+	let line = self.history_line(i);
+	let last_col = |line: &[u8]| line.len().saturating_sub(1);
+	let page = cmp::max(self.lines.vis_len(), 2) - 1;
+	let to = match key {
+	    CopyKey::Left => (i, c.saturating_sub(1)),
+	    CopyKey::Right => (i, cmp::min(c+1, last_col(line))),
+	    CopyKey::Up => (i.saturating_sub(1), c),
+	    CopyKey::Down => (cmp::min(i+1, len-1), c),
+	    CopyKey::LineStart => (i, 0),
+	    CopyKey::FirstChar => (i, word_start_from(line, 0).unwrap_or(0)),
+	    CopyKey::LineEnd => (i, last_col(line)),
+	    CopyKey::WordNext => {
+		let cls = line.get(c).map_or(0, |b| class(*b));
+		let mut e = c;
+		while e < line.len() && class(line[e]) == cls {e += 1};
+		word_start_from(line, e).map(|s| (i, s))
+		    .or_else(|| (i+1..len).find_map(|j| {
+			let l = self.history_line(j);
+			if l.is_empty() {Some((j, 0))} else {word_start_from(l, 0).map(|s| (j, s))}
+		    }))
+		    .unwrap_or((i, c))
+	    },
+	    CopyKey::WordEnd => word_end_from(line, c+1).map(|e| (i, e))
+		.or_else(|| (i+1..len).find_map(|j| word_end_from(self.history_line(j), 0).map(|e| (j, e))))
+		.unwrap_or((i, c)),
+	    CopyKey::WordPrev => word_start_before(line, cmp::min(c, line.len())).map(|s| (i, s))
+		.or_else(|| (0..i).rev().find_map(|j| {
+		    let l = self.history_line(j);
+		    if l.is_empty() {Some((j, 0))} else {word_start_before(l, l.len()).map(|s| (j, s))}
+		}))
+		.unwrap_or((i, c)),
+	    CopyKey::Top => (0, 0),
+	    CopyKey::Bottom => (len-1, 0),
+	    CopyKey::HalfPageUp => (i.saturating_sub(page/2+1), c),
+	    CopyKey::HalfPageDown => (cmp::min(i+page/2+1, len-1), c),
+	    CopyKey::PageUp => (i.saturating_sub(page), c),
+	    CopyKey::PageDown => (cmp::min(i+page, len-1), c),
+	    CopyKey::Select(kind) => {
+		match self.selection.as_ref().map(|sel| sel.kind) {
+		    Some(k) if k == kind => self.selection = None,
+		    Some(_) => if let Some(sel) = &mut self.selection {sel.kind = kind},
+		    None => self.selection = Some(Selection::new(kind, (i, c))),
+		}
+		self.draw(None);
+		return None;
+	    },
+	    CopyKey::Quote => {
+		if let Some(copy) = &mut self.copy {copy.quote = true};
+		return None;
+	    },
+	    CopyKey::Register(r) => {
+		if let (true, Some(copy)) = (quote, &mut self.copy) {
+		    copy.clip = if r == '*' {Clip::Primary} else {Clip::Clipboard};
+		}
+		return None;
+	    },
+	    CopyKey::Yank => {
+		let res = self.selection.as_ref().map(|_| (clip, self.selection_text()));
+		self.end_copy();
+		return res;
+	    },
+	    CopyKey::Escape if self.selection.is_some() => {
+		self.clear_selection();
+		return None;
+	    },
+	    CopyKey::Escape | CopyKey::Quit => {
+		self.end_copy();
+		return None;
+	    },
+	};
+	// vertical moves keep the column where the line is long enough
+	let to = (to.0, cmp::min(to.1, last_col(self.history_line(to.0))));
+	self.copy_move(to);
+	None
+    }
+    fn copy_move(&mut self, to: (usize, usize)) {
+	if let Some(copy) = &mut self.copy {copy.cursor = to};
+	if let Some(sel) = &mut self.selection {sel.head = to};
+	self.show_history_line(to.0);
+	self.draw(None);
+    }
+    pub(super) fn end_copy(&mut self) {
+	if self.copy.take().is_none() {return};
+	self.selection = None;
+	self.scrollback.offset = 0;
+	self.draw(None);
+    }
+    // column of the cursor in row n of the window, the copy mode one while it lasts
+    pub fn view_cursor(&self, n: usize) -> Option<usize> {
+	let i = (self.scrollback.len() + n).checked_sub(self.scrollback.offset)?;
+	match &self.copy {
+	    Some(copy) => if copy.cursor.0 == i {Some(copy.cursor.1)} else {None},
+	    None => if i.checked_sub(self.scrollback.len()) == self.lines.cur().checked_sub(self.lines.start) {
+		Some(self.col)
+	    } else {None},
+	}
+    }
+}
+
+#[cfg(test)]
+mod copy_tests {
+    use super::*;
+    use crate::t::state_test::*;
+    #[test]
+    fn motions() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.write_all(b"one two.three\n\n  four").unwrap();
+	state.start_copy();
+	assert_eq!((state.view_cursor(2), state.view_cursor(0)), (Some(6), None));
+	let keys = [
+	    (CopyKey::FirstChar, (2, 2)), (CopyKey::WordPrev, (1, 0)), (CopyKey::WordPrev, (0, 8)),
+	    (CopyKey::WordPrev, (0, 7)), (CopyKey::WordPrev, (0, 4)), (CopyKey::WordEnd, (0, 6)),
+	    (CopyKey::WordEnd, (0, 7)), (CopyKey::WordNext, (0, 8)), (CopyKey::WordNext, (1, 0)),
+	    (CopyKey::WordNext, (2, 2)), (CopyKey::Top, (0, 0)), (CopyKey::LineEnd, (0, 12)),
+	    (CopyKey::Down, (1, 0)), (CopyKey::Bottom, (2, 0)), (CopyKey::Right, (2, 1)),
+	];
+	for (key, at) in keys {
+	    state.copy_key(key);
+	    assert_eq!(state.copy.as_ref().unwrap().cursor, at, "{:?}", key);
+	}
+    }
+    #[test]
+    fn yank() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.write_all(b"one two\nthree").unwrap();
+	state.start_copy();
+	state.write_all(b"!").unwrap();
+	for key in [CopyKey::Up, CopyKey::LineStart, CopyKey::Select(SelectKind::Char), CopyKey::WordEnd, CopyKey::Down] {
+	    state.copy_key(key);
+	}
+	assert_eq!(state.selection_marks(0), Some((0, usize::MAX)));
+	assert_eq!(state.lines[1], b"three!");
+	for key in [CopyKey::Quote, CopyKey::Register('*')] {
+	    state.copy_key(key);
+	}
+	assert_eq!(state.copy_key(CopyKey::Yank), Some((Clip::Primary, b"one two\nthr".to_vec())));
+	assert!(!state.copying());
+    }
+    #[test]
+    fn output() {
+	let mut config = Config::new();
+	(config.n_lines, config.scrollback) = (2, 1);
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all(b"a\nb").unwrap();
+	state.start_copy();
+	state.write_all(b"\nc\nd\x1b[6n").unwrap();   // a is dropped, b goes to the scrollback
+	assert_eq!(state.take_reply(), b"\x1b[2;2R");
+	assert_eq!((state.copy.as_ref().unwrap().cursor, state.view_offset()), ((0, 1), 1));
+	assert_eq!((state.view_line(0).0, state.view_cursor(0)), (&b"b"[..], Some(1)));
+	state.copy_key(CopyKey::Quit);
+	assert_eq!((state.view_offset(), state.view_line(0).0), (0, &b"c"[..]));
+    }
+}
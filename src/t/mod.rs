@@ -1,66 +1,296 @@
 pub mod state;
+pub mod parse;
 pub mod write;
 pub mod window;
 pub mod pty;
+pub mod trace;
+pub mod color;
+pub mod sixel;
+pub mod caps;
+pub mod width;
+pub mod search;
+pub mod select;
+pub mod copy;
+pub mod reflow;
 
 use std::io;
 use std::collections::HashSet;
-use state::{QueueView,Reader,Config};
-use write::*;
+use std::time::Instant;
+use state::{QueueView,Scrollback,Reader,Config,Modes,CursorShape,LineSize};
+use parse::Parser;
+use write::Dcs;
+use color::{Colors,Rgb};
+use sixel::Placement;
+use search::Search;
+use select::{Selection,SelectKind};
+use copy::CopyMode;
 pub use window::Window;
-pub use pty::Pty;
+pub use pty::{Pty,KeyEvent};
+pub use trace::{Trace,TraceTarget};
 
 pub struct State<'b, T>
 where
     T: FnMut(Option<usize>)
 {
     lines: QueueView<Vec<u8>>,
-    leftover: EscSeq,
+    scrollback: Scrollback,
+    parser: Parser,
+    inv: bool,   // background colour set, spaces shown as '#'
     pub col: usize,
     draw: Option<&'b mut T>,
     pub config: Config,
     redraw_lines: Option<HashSet<usize>>,
+    keyboard: Vec<u8>,  // kitty keyboard protocol flag stack
+    reply: Vec<u8>,
+    modes: Modes,
+    trace: Option<Trace>,
+    cell_size: (u16, u16),   // pixels per character and line, known once there is a Window
+    screen_size: (u16, u16),   // pixels, for the window position report
+    colors: Colors,
+    dcs: Option<Dcs>,
+    images: Vec<Placement>,
+    line_sizes: Vec<(usize, LineSize)>,   // rows as in QueueView
+    wrapped: Vec<usize>,   // rows as in QueueView that continue on the next one after autowrap
+    search: Option<Search>,
+    selection: Option<Selection>,
+    drag: Option<(SelectKind, (usize, usize))>,   // button 1 pressed, where and how the selection would start
+    copy: Option<CopyMode>,
 }
 impl<T: FnMut(Option<usize>)> State<'_,T> {
     pub fn new() -> State<'static, T>
     {
-	let mut config = Config::new();
+	Self::with_config(Config::new())
+    }
+    pub fn with_config<'a>(config: Config) -> State<'a, T>
+    {
 	State {
 	    lines: QueueView::<Vec<u8>>::new(config.n_lines),
-	    leftover: EscSeq {
-		esc: Esc::None,
-		seq: Vec::new(),
-		inv: false,
-	    },
+	    scrollback: Scrollback::new(config.scrollback),
+	    parser: Parser::new(),
+	    inv: false,
 	    col: 0,
 	    draw: None,
 	    config: config,
 	    redraw_lines: Some(HashSet::new()),
+	    keyboard: Vec::new(),
+	    reply: Vec::new(),
+	    modes: Modes::new(),
+	    trace: None,
+	    cell_size: (0, 0),
+	    screen_size: (0, 0),
+	    colors: Colors::new(),
+	    dcs: None,
+	    images: Vec::new(),
+	    line_sizes: Vec::new(),
+	    wrapped: Vec::new(),
+	    search: None,
+	    selection: None,
+	    drag: None,
+	    copy: None,
+	}
+    }
+    // RIS, everything but the config, the draw callback and pending replies back to its initial value
+    fn reset(&mut self) {
+	let config = std::mem::replace(&mut self.config, Config::new());   // placeholder, reads no environment
+	let old = std::mem::replace(self, State::with_config(config));
+	self.draw = old.draw;
+	self.redraw_lines = old.redraw_lines;
+	self.reply = old.reply;
+	self.trace = old.trace;
+	self.cell_size = old.cell_size;
+	self.screen_size = old.screen_size;
+	self.scrollback = old.scrollback;
+	self.scrollback.offset = 0;
+	self.draw(None);
+    }
+    // DECSTR, resets modes and the keyboard flags but keeps the screen content, DECSCNM and bracketed paste
+    fn soft_reset(&mut self) {
+	self.modes = Modes {
+	    reverse: self.modes.reverse,
+	    bracketed_paste: self.modes.bracketed_paste,
+	    ..Modes::new()
+	};
+	self.keyboard.clear();
+	self.inv = false;
+	self.draw(Some(self.lines.cur()));
+    }
+    pub fn keyboard_flags(&self) -> u8 {
+	self.keyboard.last().copied().unwrap_or(0)
+    }
+    // bytes to send back to the client, e.g. answers to queries
+    pub fn take_reply(&mut self) -> Vec<u8> {
+	if let Some(trace) = &mut self.trace {
+	    if !self.reply.is_empty() {trace.bytes("reply", &self.reply)};
+	    trace.flush();
 	}
+	std::mem::take(&mut self.reply)
+    }
+    // bytes for the client from pasted text, marked as such if the client asked for it
+    pub fn paste(&self, text: &[u8]) -> Vec<u8> {
+	if !self.modes.bracketed_paste {return text.to_vec()};
+	let mut res = Vec::with_capacity(text.len()+12);
+	res.extend_from_slice(b"\x1b[200~");
+	let mut rest = text;
+	while let Some(i) = rest.windows(6).position(|w| w == b"\x1b[201~") {   // the paste must not end early
+	    res.extend_from_slice(&rest[..i]);
+	    rest = &rest[i+6..];
+	}
+	res.extend_from_slice(rest);
+	res.extend_from_slice(b"\x1b[201~");
+	res
+    }
+    pub fn set_cell_size(&mut self, width: u16, height: u16) {
+	self.cell_size = (width, height);
+    }
+    pub fn set_screen_size(&mut self, width: u16, height: u16) {
+	self.screen_size = (width, height);
+    }
+    // size of the page for the pty, in pixels as in the CSI 14 t report
+    pub fn winsize(&self) -> nix::pty::Winsize {
+	let (c_w, c_h) = self.cell_size;
+	let (rows, cols) = (self.lines.max as u16, self.config.line_width);
+	nix::pty::Winsize {
+	    ws_row: rows,
+	    ws_col: cols,
+	    ws_xpixel: cols.saturating_mul(c_w),
+	    ws_ypixel: rows.saturating_mul(c_h),
+	}
+    }
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+	self.trace = trace;
+    }
+    // start tracing as set in config.trace
+    pub fn open_trace(&mut self) -> io::Result<()> {
+	self.trace = match &self.config.trace {
+	    Some(target) => Some(Trace::open(target)?),
+	    None => None,
+	};
+	Ok(())
     }
     pub fn do_and_redraw<F>(&mut self, f: F, window: &mut Window)
     where
 	F: FnOnce(&mut State<T>)
     {
 	f(self);
+	if self.synchronized() {return};   // keep collecting redraw_lines until ESU
+	self.modes.sync = None;
 	window.redraw(self);
 	self.redraw_lines = Some(HashSet::new());
     }
+    pub fn synchronized(&self) -> bool {
+	self.sync_deadline().map_or(false, |d| Instant::now() < d)
+    }
+    pub fn cursor_shape(&self) -> CursorShape {
+	self.modes.cursor_shape.unwrap_or(self.config.cursor_shape)
+    }
+    pub fn cursor_blinks(&self) -> bool {
+	self.modes.cursor_visible && self.modes.cursor_blink.unwrap_or(self.config.cursor_blink)
+    }
+    pub fn line_size(&self, row: usize) -> Option<LineSize> {
+	self.line_sizes.iter().find(|(r, _)| *r == row).map(|(_, size)| *size)
+    }
+    // judged by the background so that themes and OSC 11 count as well
+    pub fn dark(&self) -> bool {
+	let bg = self.fg_bg().1;
+	let (r, g, b) = (bg >> 16 & 0xff, bg >> 8 & 0xff, bg & 0xff);
+	r*299 + g*587 + b*114 < 128*1000
+    }
+    // foreground and background as drawn
+    pub fn fg_bg(&self) -> (Rgb, Rgb) {
+	let (fg, bg) = if self.config.dark {
+	    (self.config.background, self.config.foreground)
+	} else {
+	    (self.config.foreground, self.config.background)
+	};
+	let (fg, bg) = (self.colors.fg.unwrap_or(fg), self.colors.bg.unwrap_or(bg));
+	if self.modes.reverse {(bg, fg)} else {(fg, bg)}
+    }
+    pub fn cursor_color(&self) -> Rgb {
+	self.colors.cursor.or(self.config.cursor_color).unwrap_or(self.fg_bg().0)
+    }
+    pub fn palette(&self, i: u8) -> Rgb {
+	self.colors.palette[i as usize].unwrap_or_else(|| color::palette_default(&self.config.palette, i))
+    }
+    pub fn redraw_cursor(&mut self) {
+	self.draw(Some(self.lines.cur()));
+    }
+    // view moved back into the scrollback by n lines, forward if negative
+    pub fn scroll_view(&mut self, n: isize) {
+	if self.scrollback.scroll(n) {self.draw(None)};
+    }
+    pub fn view_offset(&self) -> usize {
+	self.scrollback.offset
+    }
+    // line shown at row n of the window and its row in lines, None for one from the scrollback
+    pub fn view_line(&self, n: usize) -> (&[u8], Option<usize>) {
+	let i = self.scrollback.len() + n - self.scrollback.offset;
+	let row = i.checked_sub(self.scrollback.len()).map(|row| row + self.lines.start);
+	(self.history_line(i), row)
+    }
+    // highlighted columns in row n of the window, true for the current search match
+    pub fn marks(&self, n: usize) -> Vec<(usize, usize, bool)> {
+	let mut marks = self.search_marks(n);
+	marks.extend(self.selection_marks(n).map(|(s, e)| (s, e, false)));
+	marks
+    }
+    // the scrollback followed by the visible lines
+    fn history_len(&self) -> usize {
+	self.scrollback.len() + self.lines.vis_len()
+    }
+    fn history_line(&self, i: usize) -> &[u8] {
+	if i < self.scrollback.len() {
+	    &self.scrollback[i]
+	} else {
+	    &self.lines[self.lines.start + i - self.scrollback.len()]
+	}
+    }
+    // view scrolled as little as possible to show history line i
+    fn show_history_line(&mut self, i: usize) {
+	let (len, rows) = (self.scrollback.len(), self.lines.vis_len());
+	let top = len - self.scrollback.offset;
+	let offset = if i < top {len - i} else if i >= top+rows {(len+rows-1).saturating_sub(i)} else {return};
+	self.scrollback.offset = offset;
+	self.draw(None);
+    }
+    // time at which a pending synchronized update is drawn even without ESU
+    pub fn sync_deadline(&self) -> Option<Instant> {
+	self.modes.sync.map(|start| start + self.config.sync_timeout)
+    }
+}
+
+pub const BSU: &[u8] = b"\x1b[?2026h";
+pub const ESU: &[u8] = b"\x1b[?2026l";
+// run f between BSU and ESU so that the terminal draws all its output at once
+pub fn synchronized<W, F>(w: &mut W, f: F) -> io::Result<()>
+where
+    W: io::Write,
+    F: FnOnce(&mut W) -> io::Result<()>
+{
+    w.write_all(BSU)?;
+    let res = f(w);
+    w.write_all(ESU)?;
+    res
 }
 impl<T: FnMut(Option<usize>)> io::Write for State<'_, T> {
     fn flush(&mut self) -> io::Result<()> {
-	if self.leftover.esc == Esc::None && self.leftover.seq.is_empty() {
+	if self.parser.is_ground() {
 	    Ok(())
 	} else {
 	    Err(io::Error::new(io::ErrorKind::Other,"Incomplete control sequence cannot be flushed!"))
 	}
     }
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-	let mut esc_seq = self.leftover.clone();
-	let l = self.pre_write(buf, &mut esc_seq);
-	esc_seq.inv = self.leftover.inv;
-	self.leftover = esc_seq;
-	Ok(l)
+	if let Some(trace) = &mut self.trace {trace.bytes("bytes", buf)};
+	if self.scrollback.offset > 0 && !buf.is_empty() && self.copy.is_none() {   // new output is shown at the bottom
+	    self.scrollback.offset = 0;
+	    self.draw(None);
+	}
+	let before = (self.scrollback.len(), self.scrollback.dropped);
+	let mut parser = std::mem::take(&mut self.parser);
+	parser.advance(self, buf);
+	self.parser = parser;
+	self.follow_history(before);
+	Ok(buf.len())
     }
 }
 impl<T: FnMut(Option<usize>)> State<'_, T> {
@@ -89,16 +319,9 @@ mod state_test {
 	let mut draw_cmds = String::new();
 	let mut draw = Box::new(|b| draw_cmds += &format!("<{:?}>",b)[..]) as Box<dyn FnMut(Option<usize>) + '_>;
 	let mut state = State {
-	    lines: QueueView::<Vec<u8>>::new(),
-	    leftover: EscSeq {
-		esc: Esc::None,
-		seq: Vec::new(),
-		inv: false,
-	    },
-	    col: 0,
 	    draw: Some(&mut draw),
-	    config: Config::new(),
 	    redraw_lines: None,
+	    ..State::new()
 	};
 	f(&mut state);
 	state.draw = None;
@@ -115,4 +338,123 @@ mod state_test {
 	});
 	assert_eq!(draw_text, "<Some(0)>");
     }
+    #[test]
+    fn scrollback() {
+	let mut config = Config::new();
+	config.n_lines = 2;
+	config.scrollback = 2;
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all(b"a\nb\nc\nd").unwrap();
+	assert_eq!((state.scrollback.len(), &state.scrollback[0][..], &state.scrollback[1][..]), (2, &b"a"[..], &b"b"[..]));
+	state.scroll_view(5);
+	assert_eq!((state.view_offset(), state.view_line(0), state.view_line(1)), (2, (&b"a"[..], None), (&b"b"[..], None)));
+	state.scroll_view(-1);
+	assert_eq!((state.view_line(0), state.view_line(1)), ((&b"b"[..], None), (&b"c"[..], Some(0))));
+	state.write_all(b"e").unwrap();
+	assert_eq!((state.view_offset(), state.view_line(1)), (0, (&b"de"[..], Some(1))));
+	state.write_all(b"\x1b[S").unwrap();
+	assert_eq!(&state.scrollback[1][..], b"c");
+	state.scroll_view(1);
+	state.write_all(b"\x1b[3J").unwrap();
+	assert_eq!((state.scrollback.len(), state.view_offset()), (0, 0));
+    }
+    #[test]
+    fn paste() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	assert_eq!(state.paste(b"ls\n"), b"ls\n");
+	state.write_all(b"\x1b[?2004h\x1b[?2004$p").unwrap();
+	assert_eq!(state.take_reply(), b"\x1b[?2004;1$y");
+	assert_eq!(state.paste(b"a\x1b[201~\x1b[201~b\n"), b"\x1b[200~ab\n\x1b[201~");
+	state.write_all(b"\x1b[?2004l").unwrap();
+	assert_eq!(state.paste(b"a\x1b[201~"), b"a\x1b[201~");
+    }
+    #[test]
+    fn winsize() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.set_cell_size(6, 16);
+	let size = |st: &State<Box<dyn FnMut(Option<usize>)>>| {
+	    let w = st.winsize();
+	    (w.ws_row, w.ws_col, w.ws_xpixel, w.ws_ypixel)
+	};
+	assert_eq!(size(&state), (24, 50, 300, 384));
+	state.write_all(b"\x1b[3;73~").unwrap();
+	assert_eq!(size(&state), (3, 50, 300, 48));
+	state.config.window_ops = true;
+	state.write_all(b"\x1b[8;;20t").unwrap();
+	assert_eq!(size(&state), (3, 20, 120, 48));
+    }
+    #[test]
+    fn synchronized_update() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	let res = synchronized(&mut state, |st| {
+	    st.write_all(b"a\x1b[?2026$p")?;
+	    assert!(st.synchronized());
+	    assert_eq!(st.take_reply(), b"\x1b[?2026;1$y");
+	    Ok(())
+	});
+	assert!(res.is_ok());
+	assert!(!state.synchronized());
+	state.write_all(b"\x1b[?2026$p").unwrap();
+	assert_eq!(state.take_reply(), b"\x1b[?2026;2$y");
+	state.config.sync_timeout = std::time::Duration::ZERO;
+	state.write_all(BSU).unwrap();
+	assert!(!state.synchronized());
+    }
+    #[test]
+    fn reset() {
+	let draw_text = draw_test(|state| {
+	    state.write_all(b"a\nb\x1b[>1u\x1b[?25l\x1b[?5h\x1b[41m\x1b[?996n").unwrap();  // ... <None>
+	    state.write_all(b"\x1b[?7l\x1b[3 q\x1b[?2004h\x1b[?69h\x1b[?2026h").unwrap();
+	    state.write_all(b"\x1b[!p").unwrap();  // <Some(1)>
+	    assert!(state.modes.cursor_visible && state.modes.reverse && !state.inv);
+	    assert!(state.modes.autowrap && state.modes.bracketed_paste && !state.modes.lr_margins);
+	    state.write_all(b"\x1b[?5$p\x1b[?2004$p").unwrap();
+	    assert_eq!(state.take_reply(), b"\x1b[?997;1n\x1b[?5;1$y\x1b[?2004;1$y");
+	    assert_eq!((state.modes.cursor_shape, state.modes.cursor_blink, state.modes.sync), (None, None, None));
+	    assert_eq!((state.keyboard_flags(), &state.lines[1][..]), (0, &b"b"[..]));
+	    state.write_all(b"\x1b[>1u\x1bc").unwrap();  // <Some(1)><None>
+	    assert!(!state.modes.reverse);
+	    assert_eq!(state.keyboard_flags(), 0);
+	    assert_eq!((state.lines.cur(), state.col, state.lines.len()), (0, 0, 1));
+	});
+	assert!(draw_text.ends_with("<Some(1)><None><Some(1)><Some(1)><None>"));
+    }
+    #[test]
+    fn cursor_modes() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.write_all(b"\x1b[?25l\x1b[5 q").unwrap();
+	assert!(!state.modes.cursor_visible);
+	assert!(!state.cursor_blinks());
+	assert_eq!(state.cursor_shape(), CursorShape::Bar);
+	state.write_all(b"\x1b[?25h\x1b[?12l\x1b[?25$p").unwrap();
+	assert!(!state.cursor_blinks());
+	assert_eq!(state.take_reply(), b"\x1b[?25;1$y");
+	state.write_all(b"\x1b[3 q").unwrap();
+	assert!(state.cursor_blinks());
+	assert_eq!(state.cursor_shape(), CursorShape::Underline);
+	state.config.cursor_shape = CursorShape::Block;
+	state.write_all(b"\x1b[ q").unwrap();
+	assert!(!state.cursor_blinks());
+	assert_eq!(state.cursor_shape(), CursorShape::Block);
+    }
+    #[test]
+    fn garbage() {
+	draw_test(|state| {
+	    let mut x: u32 = 7;
+	    let bytes: Vec<u8> = (0..200000).map(|_| {
+		x = x.wrapping_mul(1103515245).wrapping_add(12345);
+		match x >> 29 {   // mostly sequence introducers and parameters
+		    0 => 0x1b,
+		    1 => b'[',
+		    2 => 0x9b,
+		    3 => b"0123456789;:?"[(x >> 8) as usize % 13],
+		    _ => (x >> 16) as u8,
+		}
+	    }).collect();
+	    for chunk in bytes.chunks(61) {
+		state.write_all(chunk).unwrap();
+	    }
+	    assert!(state.lines.len() <= state.lines.max);
+	});
+    }
 }
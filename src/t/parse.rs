@@ -0,0 +1,416 @@
+// VT parser after the DEC/Paul Williams state diagram, see https://vt100.net/emu/dec_ansi_parser
+// input is treated as UTF-8, C1 controls are accepted as ESC Fe, as U+0080..U+009F and as raw bytes
+
+use std::fmt;
+
+const MAX_PARAMS: usize = 32;
+const MAX_INTERMEDIATES: usize = 2;
+const MAX_OSC_PARAMS: usize = 16;
+const MAX_OSC_LEN: usize = 1 << 16;
+
+pub trait Perform {
+    // a run of printable characters, always complete UTF-8
+    fn print(&mut self, text: &[u8]);
+    // C0 or C1 control, ESC Fe sequences arrive here as their C1 equivalent
+    fn execute(&mut self, byte: u8);
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: u8);
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8);
+    // DCS: hook at the final byte, put for the data string, unhook at its end
+    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _action: u8) {}
+    fn put(&mut self, _data: &[u8]) {}
+    fn unhook(&mut self) {}
+    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {}
+}
+
+// numeric parameters of a CSI or DCS sequence, ':' separated sub parameters form a group
+#[derive(Default)]
+pub struct Params {
+    values: [Option<u16>; MAX_PARAMS],
+    sub: [bool; MAX_PARAMS],   // value belongs to the group of the previous one
+    len: usize,
+}
+impl Params {
+    pub fn is_empty(&self) -> bool {
+	self.len == 0
+    }
+    // first value of group i, None if it was omitted
+    pub fn get(&self, i: usize) -> Option<u16> {
+	self.iter().nth(i).and_then(|group| group[0])
+    }
+    pub fn iter(&self) -> impl Iterator<Item=&[Option<u16>]> + '_ {
+	let mut start = 0;
+	(0..self.len).filter(move |i| i+1 == self.len || !self.sub[i+1]).map(move |end| {
+	    let group = &self.values[start..=end];
+	    start = end+1;
+	    group
+	})
+    }
+    fn clear(&mut self) {
+	self.len = 0;
+    }
+    fn push(&mut self, sub: bool) {
+	if self.len < MAX_PARAMS {
+	    self.values[self.len] = None;
+	    self.sub[self.len] = sub;
+	    self.len += 1;
+	}
+    }
+    fn param(&mut self, byte: u8) {
+	if self.len == 0 {self.push(false)};
+	match byte {
+	    b';' => self.push(false),
+	    b':' => self.push(true),
+	    _ => {
+		let v = &mut self.values[self.len-1];
+		*v = Some(v.unwrap_or(0).saturating_mul(10).saturating_add((byte-b'0').into()));
+	    },
+	}
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	for (i, v) in self.values[..self.len].iter().enumerate() {
+	    if i > 0 {f.write_str(if self.sub[i] {":"} else {";"})?};
+	    if let Some(v) = v {write!(f, "{}", v)?};
+	}
+	Ok(())
+    }
+}
+
+#[derive(PartialEq,Copy,Clone,Debug,Default)]
+enum VtState {
+    #[default]
+    Ground,
+    Escape,
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
+}
+const STATES: [VtState; 14] = [
+    VtState::Ground, VtState::Escape, VtState::EscapeIntermediate,
+    VtState::CsiEntry, VtState::CsiParam, VtState::CsiIntermediate, VtState::CsiIgnore,
+    VtState::DcsEntry, VtState::DcsParam, VtState::DcsIntermediate, VtState::DcsPassthrough, VtState::DcsIgnore,
+    VtState::OscString, VtState::SosPmApcString,
+];
+#[derive(PartialEq,Copy,Clone,Debug)]
+enum Action {
+    None,
+    Print,
+    Execute,
+    Collect,
+    Param,
+    EscDispatch,
+    CsiDispatch,
+    Put,
+    OscPut,
+}
+const ACTIONS: [Action; 9] = [
+    Action::None, Action::Print, Action::Execute, Action::Collect, Action::Param,
+    Action::EscDispatch, Action::CsiDispatch, Action::Put, Action::OscPut,
+];
+
+// table entries are action<<4 | next state, STAY for transitions without state change
+const STAY: u8 = 0x0f;
+// all non-ASCII characters share the transitions of this byte, it is a C1 control otherwise unused here
+const NON_ASCII: u8 = 0xa0;
+static TABLE: [[u8; 256]; 14] = table();
+
+const fn entry(action: Action, next: u8) -> u8 {
+    (action as u8) << 4 | next
+}
+const fn to(state: VtState) -> u8 {
+    state as u8
+}
+const fn transition(state: VtState, byte: u8) -> u8 {
+    use Action::*;
+    use VtState::*;
+    let c0 = matches!(byte, 0x00..=0x17 | 0x19 | 0x1c..=0x1f);
+    // transitions from anywhere
+    match byte {
+	0x18 | 0x1a | 0x80..=0x8f | 0x91..=0x97 | 0x99 | 0x9a => return entry(Execute, to(Ground)),
+	0x9c => return entry(None, to(Ground)),
+	0x1b => return entry(None, to(Escape)),
+	0x90 => return entry(None, to(DcsEntry)),
+	0x9b => return entry(None, to(CsiEntry)),
+	0x9d => return entry(None, to(OscString)),
+	0x98 | 0x9e | 0x9f => return entry(None, to(SosPmApcString)),
+	_ => (),
+    }
+    match state {
+	Ground => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x7e | NON_ASCII => entry(Print, STAY),
+	    _ => entry(None, STAY),
+	},
+	Escape => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x2f => entry(Collect, to(EscapeIntermediate)),
+	    b'[' => entry(None, to(CsiEntry)),
+	    b']' => entry(None, to(OscString)),
+	    b'P' => entry(None, to(DcsEntry)),
+	    b'X' | b'^' | b'_' => entry(None, to(SosPmApcString)),
+	    0x30..=0x7e => entry(EscDispatch, to(Ground)),
+	    NON_ASCII => entry(None, to(Ground)),
+	    _ => entry(None, STAY),
+	},
+	EscapeIntermediate => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x2f => entry(Collect, STAY),
+	    0x30..=0x7e => entry(EscDispatch, to(Ground)),
+	    NON_ASCII => entry(None, to(Ground)),
+	    _ => entry(None, STAY),
+	},
+	CsiEntry => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x2f => entry(Collect, to(CsiIntermediate)),
+	    0x30..=0x3b => entry(Param, to(CsiParam)),
+	    0x3c..=0x3f => entry(Collect, to(CsiParam)),
+	    0x40..=0x7e => entry(CsiDispatch, to(Ground)),
+	    NON_ASCII => entry(None, to(CsiIgnore)),
+	    _ => entry(None, STAY),
+	},
+	CsiParam => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x2f => entry(Collect, to(CsiIntermediate)),
+	    0x30..=0x3b => entry(Param, STAY),
+	    0x3c..=0x3f | NON_ASCII => entry(None, to(CsiIgnore)),
+	    0x40..=0x7e => entry(CsiDispatch, to(Ground)),
+	    _ => entry(None, STAY),
+	},
+	CsiIntermediate => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x20..=0x2f => entry(Collect, STAY),
+	    0x30..=0x3f | NON_ASCII => entry(None, to(CsiIgnore)),
+	    0x40..=0x7e => entry(CsiDispatch, to(Ground)),
+	    _ => entry(None, STAY),
+	},
+	CsiIgnore => match byte {
+	    _ if c0 => entry(Execute, STAY),
+	    0x40..=0x7e => entry(None, to(Ground)),
+	    _ => entry(None, STAY),
+	},
+	DcsEntry => match byte {
+	    0x20..=0x2f => entry(Collect, to(DcsIntermediate)),
+	    0x30..=0x3b => entry(Param, to(DcsParam)),
+	    0x3c..=0x3f => entry(Collect, to(DcsParam)),
+	    0x40..=0x7e => entry(None, to(DcsPassthrough)),
+	    NON_ASCII => entry(None, to(DcsIgnore)),
+	    _ => entry(None, STAY),
+	},
+	DcsParam => match byte {
+	    0x20..=0x2f => entry(Collect, to(DcsIntermediate)),
+	    0x30..=0x3b => entry(Param, STAY),
+	    0x3c..=0x3f | NON_ASCII => entry(None, to(DcsIgnore)),
+	    0x40..=0x7e => entry(None, to(DcsPassthrough)),
+	    _ => entry(None, STAY),
+	},
+	DcsIntermediate => match byte {
+	    0x20..=0x2f => entry(Collect, STAY),
+	    0x30..=0x3f | NON_ASCII => entry(None, to(DcsIgnore)),
+	    0x40..=0x7e => entry(None, to(DcsPassthrough)),
+	    _ => entry(None, STAY),
+	},
+	DcsPassthrough => match byte {
+	    _ if c0 => entry(Put, STAY),
+	    0x20..=0x7e | NON_ASCII => entry(Put, STAY),
+	    _ => entry(None, STAY),
+	},
+	OscString => match byte {
+	    0x07 => entry(None, to(Ground)),   // xterm accepts BEL as string terminator
+	    0x20..=0x7e | NON_ASCII => entry(OscPut, STAY),
+	    _ => entry(None, STAY),
+	},
+	DcsIgnore | SosPmApcString => entry(None, STAY),
+    }
+}
+const fn table() -> [[u8; 256]; 14] {
+    let mut table = [[0; 256]; 14];
+    let mut s = 0;
+    while s < STATES.len() {
+	let mut b = 0;
+	while b < 256 {
+	    table[s][b] = transition(STATES[s], b as u8);
+	    b += 1;
+	}
+	s += 1;
+    }
+    table
+}
+
+#[derive(Default)]
+pub struct Parser {
+    state: VtState,
+    params: Params,
+    intermediates: [u8; MAX_INTERMEDIATES],
+    n_intermediates: usize,
+    ignoring: bool,   // too many intermediates, the sequence is dispatched with ignore set
+    osc: Vec<u8>,
+    utf8: [u8; 4],
+    utf8_len: usize,
+    utf8_need: usize,
+}
+impl Parser {
+    pub fn new() -> Parser {
+	Parser::default()
+    }
+    // true if no sequence or character is incomplete
+    pub fn is_ground(&self) -> bool {
+	self.state == VtState::Ground && self.utf8_need == 0
+    }
+    pub fn advance<P: Perform>(&mut self, performer: &mut P, bytes: &[u8]) {
+	let mut i = 0;
+	while i < bytes.len() {
+	    // runs of printable ASCII do not change the state, hand them on at once
+	    let run = match (self.state, self.utf8_need) {
+		(VtState::Ground | VtState::DcsPassthrough | VtState::OscString, 0) =>
+		    bytes[i..].iter().take_while(|b| (0x20..0x7f).contains(*b)).count(),
+		_ => 0,
+	    };
+	    if run > 0 {
+		let run_bytes = &bytes[i..i+run];
+		match self.state {
+		    VtState::Ground => performer.print(run_bytes),
+		    VtState::DcsPassthrough => performer.put(run_bytes),
+		    _ => self.action(performer, Action::OscPut, 0, run_bytes),
+		}
+		i += run;
+		continue;
+	    }
+	    self.byte(performer, bytes[i]);
+	    i += 1;
+	}
+    }
+    fn byte<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+	if self.utf8_need > 0 {
+	    let valid = match (self.utf8_len, self.utf8[0]) {
+		(1, 0xe0) => (0xa0..=0xbf).contains(&byte),   // no overlong encodings
+		(1, 0xed) => (0x80..=0x9f).contains(&byte),   // no surrogates
+		(1, 0xf0) => (0x90..=0xbf).contains(&byte),
+		(1, 0xf4) => (0x80..=0x8f).contains(&byte),   // nothing above U+10FFFF
+		_ => byte & 0xc0 == 0x80,
+	    };
+	    if valid {
+		self.utf8[self.utf8_len] = byte;
+		self.utf8_len += 1;
+		if self.utf8_len == self.utf8_need {
+		    self.utf8_need = 0;
+		    self.char(performer);
+		}
+		return;
+	    }
+	    self.utf8_need = 0;
+	    self.invalid(performer);
+	}
+	match byte {
+	    0x00..=0x9f => self.transition(performer, byte, &[byte]),
+	    0xc2..=0xf4 => {
+		self.utf8[0] = byte;
+		self.utf8_len = 1;
+		self.utf8_need = match byte {
+		    0xc2..=0xdf => 2,
+		    0xe0..=0xef => 3,
+		    _ => 4,
+		};
+	    },
+	    _ => self.invalid(performer),
+	}
+    }
+    fn char<P: Perform>(&mut self, performer: &mut P) {
+	let utf8 = self.utf8;
+	let c = &utf8[..self.utf8_len];
+	if c[0] == 0xc2 && c[1] < 0xa0 {
+	    self.transition(performer, c[1], &[c[1]]);   // C1 control as U+0080..U+009F
+	} else {
+	    self.transition(performer, NON_ASCII, c);
+	}
+    }
+    fn invalid<P: Perform>(&mut self, performer: &mut P) {
+	self.transition(performer, NON_ASCII, "\u{fffd}".as_bytes());
+    }
+    // data is the byte itself or the complete UTF-8 character if byte is NON_ASCII
+    fn transition<P: Perform>(&mut self, performer: &mut P, byte: u8, data: &[u8]) {
+	let entry = TABLE[self.state as usize][byte as usize];
+	let (action, next) = (ACTIONS[(entry >> 4) as usize], entry & 0x0f);
+	if next == STAY {
+	    self.action(performer, action, byte, data);
+	} else {
+	    self.exit(performer, byte);
+	    self.action(performer, action, byte, data);
+	    self.state = STATES[next as usize];
+	    self.enter(performer, byte);
+	}
+    }
+    fn exit<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+	match self.state {
+	    VtState::OscString if byte != 0x18 && byte != 0x1a => self.osc_dispatch(performer, byte == 0x07),
+	    VtState::DcsPassthrough => performer.unhook(),
+	    _ => (),
+	}
+    }
+    fn enter<P: Perform>(&mut self, performer: &mut P, byte: u8) {
+	match self.state {
+	    VtState::Escape | VtState::CsiEntry | VtState::DcsEntry => {
+		self.params.clear();
+		self.n_intermediates = 0;
+		self.ignoring = false;
+	    },
+	    VtState::OscString => self.osc.clear(),
+	    VtState::DcsPassthrough => performer.hook(&self.params, &self.intermediates[..self.n_intermediates],
+						     self.ignoring, byte),
+	    _ => (),
+	}
+    }
+    fn action<P: Perform>(&mut self, performer: &mut P, action: Action, byte: u8, data: &[u8]) {
+	match action {
+	    Action::None => (),
+	    Action::Print => performer.print(data),
+	    Action::Execute => performer.execute(byte),
+	    Action::Collect => if self.n_intermediates < MAX_INTERMEDIATES {
+		self.intermediates[self.n_intermediates] = byte;
+		self.n_intermediates += 1;
+	    } else {
+		self.ignoring = true;
+	    },
+	    Action::Param => self.params.param(byte),
+	    Action::EscDispatch if self.n_intermediates == 0 && byte == b'\\' => (),   // ST, strings ended on ESC
+	    Action::EscDispatch => if self.n_intermediates == 0 && (0x40..=0x5f).contains(&byte) {
+		performer.execute(byte+0x40);   // ESC Fe is the 7 bit form of a C1 control
+	    } else {
+		performer.esc_dispatch(&self.intermediates[..self.n_intermediates], self.ignoring, byte);
+	    },
+	    Action::CsiDispatch => performer.csi_dispatch(&self.params, &self.intermediates[..self.n_intermediates],
+							  self.ignoring, byte),
+	    Action::Put => performer.put(data),
+	    Action::OscPut => if self.osc.len() + data.len() <= MAX_OSC_LEN {
+		self.osc.extend_from_slice(data);
+	    },
+	}
+    }
+    fn osc_dispatch<P: Perform>(&mut self, performer: &mut P, bell_terminated: bool) {
+	let mut params: [&[u8]; MAX_OSC_PARAMS] = [&[]; MAX_OSC_PARAMS];
+	let mut n = 0;
+	let mut rest = &self.osc[..];
+	while n < MAX_OSC_PARAMS-1 {
+	    match rest.iter().position(|b| *b == b';') {
+		Some(i) => {
+		    params[n] = &rest[..i];
+		    rest = &rest[i+1..];
+		    n += 1;
+		},
+		None => break,
+	    }
+	}
+	params[n] = rest;   // the last parameter keeps any further ';'
+	performer.osc_dispatch(&params[..=n], bell_terminated);
+    }
+}
@@ -1,40 +1,90 @@
 use phf::phf_map;
 use xcb::x::KeyButMask;
-use nix::pty::openpty;
+use nix::pty::{openpty,Winsize};
+use nix::unistd::setsid;
+use nix::fcntl::{fcntl,FcntlArg,OFlag};
 use std::fs::File;
-use std::os::unix::io::FromRawFd;
+use std::io::{ErrorKind,Write};
+use std::os::unix::io::{AsRawFd,FromRawFd};
+use std::os::unix::process::CommandExt;
 use std::process::{Child, Command};
 use std::env;
 use super::state::Config;
+use super::caps;
+
+nix::ioctl_write_ptr_bad!(set_winsize, nix::libc::TIOCSWINSZ, Winsize);
+nix::ioctl_write_int_bad!(set_controlling_tty, nix::libc::TIOCSCTTY);
 
 pub struct Pty {
     process: Child,
     pub fd: File,
+    size: (u16, u16, u16, u16),   // rows, columns, width and height in pixels the client was told
+    pending: Vec<u8>,   // input the client has not taken yet, the master is non-blocking
 }
 impl Pty {
-    pub fn new(config: &Config) -> Result<Pty,Box<dyn std::error::Error>> {
-	let winsize = nix::pty::Winsize {
-	    ws_row: config.n_lines,
-	    ws_col: config.line_width,
-	    ws_xpixel: config.line_width,
-	    ws_ypixel: config.n_lines,
-	};
+    // winsize as from State::winsize
+    pub fn new(config: &Config, winsize: Winsize) -> Result<Pty,Box<dyn std::error::Error>> {
 	let ends = openpty(&winsize,None)?;
 	let (master, slave) = (ends.master, ends.slave);
 	let shell = env::var("SHELL")?;
 	let mut shell_builder = Command::new(shell);
-	let builder = shell_builder.env("TERM","pcansi");
+	let term = if caps::installed(caps::NAME) {caps::NAME} else {&config.term_fallback};
+	let builder = shell_builder.env("TERM",term)
+	    .env("COLORTERM",caps::NAME)   // replaces an inherited truecolor, the sea-tf entry has no colors
+	    .env("TERM_PROGRAM","SeaTF")
+	    .env("TERM_PROGRAM_VERSION",env!("CARGO_PKG_VERSION"));
 	
-	builder.stdin(unsafe {File::from_raw_fd(slave)});
-	builder.stdout(unsafe {File::from_raw_fd(slave)});
-	builder.stderr(unsafe {File::from_raw_fd(slave)});
+	let slave = unsafe {File::from_raw_fd(slave)};   // one owner, it is closed once
+	builder.stdin(slave.try_clone()?);
+	builder.stdout(slave.try_clone()?);
+	builder.stderr(slave);
+	unsafe {   // a session of its own with the pty as controlling terminal, so the kernel sends SIGWINCH
+	    builder.pre_exec(|| {
+		setsid()?;
+		set_controlling_tty(0, 0)?;   // stdin is the slave by now
+		Ok(())
+	    });
+	}
 
+	fcntl(master, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;   // a big paste must not stop us reading the echo
 	Ok(Self {
 	    process: builder.spawn()?,
 	    fd: unsafe {File::from_raw_fd(master)},
+	    size: (winsize.ws_row, winsize.ws_col, winsize.ws_xpixel, winsize.ws_ypixel),
+	    pending: Vec::new(),
 	})
 	
     }
+    // new size for the client with TIOCSWINSZ, the kernel sends SIGWINCH to its foreground process group,
+    // nothing if the size did not change
+    pub fn resize(&mut self, winsize: Winsize) -> nix::Result<()> {
+	let size = (winsize.ws_row, winsize.ws_col, winsize.ws_xpixel, winsize.ws_ypixel);
+	if size == self.size {return Ok(())};
+	unsafe {set_winsize(self.fd.as_raw_fd(), &winsize)}?;
+	self.size = size;
+	Ok(())
+    }
+    // input for the client, queued behind what it has not taken yet
+    pub fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+	self.pending.extend_from_slice(bytes);
+	self.send_pending()
+    }
+    // as much of the queued input as the pty takes now, the rest when it polls writable
+    pub fn send_pending(&mut self) -> std::io::Result<()> {
+	while !self.pending.is_empty() {
+	    let n = std::cmp::min(self.pending.len(), 4096);
+	    match self.fd.write(&self.pending[..n]) {
+		Ok(n) => {self.pending.drain(..n);},
+		Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+		Err(e) if e.kind() == ErrorKind::Interrupted => (),
+		Err(e) => return Err(e),
+	    }
+	}
+	Ok(())
+    }
+    pub fn writing(&self) -> bool {
+	!self.pending.is_empty()
+    }
     pub fn parse_key(key_sym: xkbcommon::xkb::Keysym, mask: KeyButMask, key_sym_mod: xkbcommon::xkb::Keysym)
 		     -> String {
 	if let Some(shortcuts) = SHORTCUTS.get(&xkbcommon::xkb::keysym_get_name(key_sym)) {
@@ -49,6 +99,219 @@ impl Pty {
 	utf8.pop();
 	utf8
     }
+    // key encoding for the kitty keyboard protocol, falls back to parse_key if no flags are set
+    pub fn encode_key(flags: u8, key_sym: xkbcommon::xkb::Keysym, mask: KeyButMask,
+		      key_sym_mod: xkbcommon::xkb::Keysym, event: KeyEvent) -> String {
+	let all = flags & KKP_ALL != 0;
+	if event == KeyEvent::Release && flags & KKP_EVENTS == 0 {
+	    return String::new();
+	}
+	if event != KeyEvent::Release && !all && flags & KKP_DISAMBIGUATE == 0 {
+	    return Self::parse_key(key_sym, mask, key_sym_mod);
+	}
+	let mods = kkp_mods(mask) & if all {0xff} else {!(KKP_CAPS_LOCK|KKP_NUM_LOCK)};
+	let (code, last) = match KKP_KEYS.get(&xkbcommon::xkb::keysym_get_name(key_sym)) {
+	    Some(&(code, last)) => (code, last),
+	    None => (xkbcommon::xkb::keysym_to_utf32(key_sym), b'u'),
+	};
+	if code == 0 {
+	    return String::new();
+	}
+	if !all {
+	    let legacy = match code {
+		9 | 13 | 127 => mods == 0,               // Tab, Enter, BackSpace
+		57358..=57360 | 57441..=57454 => return String::new(),   // locks and modifiers
+		57361..=57440 => false,                  // keypad, Print, Pause, Menu and media keys
+		_ => event != KeyEvent::Release && last == b'u' && code != 27 && mods & !KKP_SHIFT == 0,
+	    };
+	    if legacy {
+		return if event == KeyEvent::Release {String::new()}
+		else {Self::parse_key(key_sym, mask, key_sym_mod)};
+	    }
+	}
+	let mut key = code.to_string();
+	if flags & KKP_ALTERNATE != 0 && mods & KKP_SHIFT != 0 && last == b'u' {
+	    let shifted = xkbcommon::xkb::keysym_to_utf32(key_sym_mod);
+	    if shifted != 0 && shifted != code {
+		key += &format!(":{}",shifted);
+	    }
+	}
+	let mut modifier = match event {
+	    _ if flags & KKP_EVENTS == 0 => format!("{}", mods as u16 + 1),
+	    KeyEvent::Press => format!("{}", mods as u16 + 1),
+	    KeyEvent::Repeat => format!("{}:2", mods as u16 + 1),
+	    KeyEvent::Release => format!("{}:3", mods as u16 + 1),
+	};
+	let mut text = String::new();
+	if flags & (KKP_ALL|KKP_TEXT) == KKP_ALL|KKP_TEXT && event != KeyEvent::Release {
+	    let mut utf8 = xkbcommon::xkb::keysym_to_utf8(key_sym_mod);
+	    utf8.pop();
+	    text = utf8.chars().filter(|c| !c.is_control())
+		.map(|c| (c as u32).to_string()).collect::<Vec<_>>().join(":");
+	}
+	if text.is_empty() {
+	    if modifier == "1" {modifier.clear()};
+	} else {
+	    text.insert(0, ';');
+	}
+	if !modifier.is_empty() || !text.is_empty() {
+	    modifier.insert(0, ';');
+	} else if key == "1" {
+	    key.clear();   // e.g. Up is CSI A
+	}
+	format!("\x1b[{}{}{}{}", key, modifier, text, last as char)
+    }
+}
+
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum KeyEvent {
+    Press,
+    Repeat,
+    Release,
+}
+// progressive enhancement flags of the kitty keyboard protocol
+pub const KKP_DISAMBIGUATE: u8 = 1;
+pub const KKP_EVENTS: u8 = 2;
+pub const KKP_ALTERNATE: u8 = 4;
+pub const KKP_ALL: u8 = 8;
+pub const KKP_TEXT: u8 = 16;
+pub const KKP_FLAGS: u8 = KKP_DISAMBIGUATE|KKP_EVENTS|KKP_ALTERNATE|KKP_ALL|KKP_TEXT;
+
+const KKP_SHIFT: u8 = 1;
+const KKP_CAPS_LOCK: u8 = 64;
+const KKP_NUM_LOCK: u8 = 128;
+fn kkp_mods(mask: KeyButMask) -> u8 {
+    [(KeyButMask::SHIFT, KKP_SHIFT),
+     (KeyButMask::MOD1, 2),
+     (KeyButMask::CONTROL, 4),
+     (KeyButMask::MOD4, 8),
+     (KeyButMask::LOCK, KKP_CAPS_LOCK),
+     (KeyButMask::MOD2, KKP_NUM_LOCK)]
+	.iter().filter(|(m,_)| mask.contains(*m)).map(|(_,b)| b).sum()
+}
+// (key code, final byte) of keys that do not simply send their unicode code point
+static KKP_KEYS: phf::Map<&'static str,(u32,u8)> = phf_map! {
+    "Escape" => (27,b'u'),
+    "Return" => (13,b'u'),
+    "Tab" => (9,b'u'),
+    "BackSpace" => (127,b'u'),
+    "Insert" => (2,b'~'),
+    "Delete" => (3,b'~'),
+    "Left" => (1,b'D'),
+    "Right" => (1,b'C'),
+    "Up" => (1,b'A'),
+    "Down" => (1,b'B'),
+    "Prior" => (5,b'~'),
+    "Next" => (6,b'~'),
+    "Home" => (1,b'H'),
+    "End" => (1,b'F'),
+    "F1" => (1,b'P'),
+    "F2" => (1,b'Q'),
+    "F3" => (13,b'~'),
+    "F4" => (1,b'S'),
+    "F5" => (15,b'~'),
+    "F6" => (17,b'~'),
+    "F7" => (18,b'~'),
+    "F8" => (19,b'~'),
+    "F9" => (20,b'~'),
+    "F10" => (21,b'~'),
+    "F11" => (23,b'~'),
+    "F12" => (24,b'~'),
+    "Caps_Lock" => (57358,b'u'),
+    "Scroll_Lock" => (57359,b'u'),
+    "Num_Lock" => (57360,b'u'),
+    "Print" => (57361,b'u'),
+    "Pause" => (57362,b'u'),
+    "Menu" => (57363,b'u'),
+    "KP_0" => (57399,b'u'),
+    "KP_1" => (57400,b'u'),
+    "KP_2" => (57401,b'u'),
+    "KP_3" => (57402,b'u'),
+    "KP_4" => (57403,b'u'),
+    "KP_5" => (57404,b'u'),
+    "KP_6" => (57405,b'u'),
+    "KP_7" => (57406,b'u'),
+    "KP_8" => (57407,b'u'),
+    "KP_9" => (57408,b'u'),
+    "KP_Decimal" => (57409,b'u'),
+    "KP_Divide" => (57410,b'u'),
+    "KP_Multiply" => (57411,b'u'),
+    "KP_Subtract" => (57412,b'u'),
+    "KP_Add" => (57413,b'u'),
+    "KP_Enter" => (57414,b'u'),
+    "KP_Equal" => (57415,b'u'),
+    "KP_Separator" => (57416,b'u'),
+    "KP_Left" => (57417,b'u'),
+    "KP_Right" => (57418,b'u'),
+    "KP_Up" => (57419,b'u'),
+    "KP_Down" => (57420,b'u'),
+    "KP_Prior" => (57421,b'u'),
+    "KP_Next" => (57422,b'u'),
+    "KP_Home" => (57423,b'u'),
+    "KP_End" => (57424,b'u'),
+    "KP_Insert" => (57425,b'u'),
+    "KP_Delete" => (57426,b'u'),
+    "KP_Begin" => (57427,b'u'),
+    "Shift_L" => (57441,b'u'),
+    "Control_L" => (57442,b'u'),
+    "Alt_L" => (57443,b'u'),
+    "Super_L" => (57444,b'u'),
+    "Hyper_L" => (57445,b'u'),
+    "Meta_L" => (57446,b'u'),
+    "Shift_R" => (57447,b'u'),
+    "Control_R" => (57448,b'u'),
+    "Alt_R" => (57449,b'u'),
+    "Super_R" => (57450,b'u'),
+    "Hyper_R" => (57451,b'u'),
+    "Meta_R" => (57452,b'u'),
+    "ISO_Level3_Shift" => (57453,b'u'),
+    "ISO_Level5_Shift" => (57454,b'u'),
+};
+
+#[cfg(test)]
+mod kkp_tests {
+    use super::*;
+    use xkbcommon::xkb::keysyms::*;
+    fn enc(flags: u8, sym: u32, mask: KeyButMask, sym_mod: u32, event: KeyEvent) -> String {
+	Pty::encode_key(flags, sym, mask, sym_mod, event)
+    }
+    #[test]
+    fn disambiguate() {
+	assert_eq!(enc(1, KEY_a, NONE, KEY_a, KeyEvent::Press), "a");
+	assert_eq!(enc(1, KEY_a, SHIFT, KEY_A, KeyEvent::Press), "A");
+	assert_eq!(enc(1, KEY_i, CONTROL, KEY_i, KeyEvent::Press), "\x1b[105;5u");
+	assert_eq!(enc(1, KEY_Tab, NONE, KEY_Tab, KeyEvent::Press), "\t");
+	assert_eq!(enc(1, KEY_Escape, NONE, KEY_Escape, KeyEvent::Press), "\x1b[27u");
+	assert_eq!(enc(1, KEY_Up, NONE, KEY_Up, KeyEvent::Press), "\x1b[A");
+	assert_eq!(enc(1, KEY_Up, CONTROL, KEY_Up, KeyEvent::Press), "\x1b[1;5A");
+	assert_eq!(enc(1, KEY_Shift_L, NONE, KEY_Shift_L, KeyEvent::Press), "");
+	assert_eq!(enc(1, KEY_a, NONE, KEY_a, KeyEvent::Release), "");
+	assert_eq!(enc(1, KEY_KP_Enter, NONE, KEY_KP_Enter, KeyEvent::Press), "\x1b[57414u");
+	assert_eq!(enc(1, KEY_KP_5, NONE, KEY_KP_5, KeyEvent::Press), "\x1b[57404u");
+	assert_eq!(enc(1, KEY_Print, NONE, KEY_Print, KeyEvent::Press), "\x1b[57361u");
+	assert_eq!(enc(1, KEY_Caps_Lock, NONE, KEY_Caps_Lock, KeyEvent::Press), "");
+    }
+    #[test]
+    fn events() {
+	assert_eq!(enc(3, KEY_a, NONE, KEY_a, KeyEvent::Repeat), "a");
+	assert_eq!(enc(3, KEY_a, NONE, KEY_a, KeyEvent::Release), "\x1b[97;1:3u");
+	assert_eq!(enc(3, KEY_Delete, NONE, KEY_Delete, KeyEvent::Repeat), "\x1b[3;1:2~");
+	assert_eq!(enc(3, KEY_Return, NONE, KEY_Return, KeyEvent::Release), "");
+    }
+    #[test]
+    fn all_keys() {
+	assert_eq!(enc(8, KEY_a, NONE, KEY_a, KeyEvent::Press), "\x1b[97u");
+	assert_eq!(enc(8, KEY_Return, NONE, KEY_Return, KeyEvent::Press), "\x1b[13u");
+	assert_eq!(enc(8, KEY_Shift_L, SHIFT, KEY_Shift_L, KeyEvent::Press), "\x1b[57441;2u");
+	assert_eq!(enc(12, KEY_a, SHIFT, KEY_A, KeyEvent::Press), "\x1b[97:65;2u");
+	assert_eq!(enc(24, KEY_a, SHIFT, KEY_A, KeyEvent::Press), "\x1b[97;2;65u");
+    }
+    #[test]
+    fn legacy() {
+	assert_eq!(enc(0, KEY_a, NONE, KEY_a, KeyEvent::Press), "a");
+	assert_eq!(enc(0, KEY_a, NONE, KEY_a, KeyEvent::Release), "");
+	assert_eq!(enc(0, KEY_Tab, SHIFT, KEY_Tab, KeyEvent::Press), "\x1b[Z");
+    }
 }
 
 
@@ -79,8 +342,7 @@ static SHORTCUTS: phf::Map<&'static str,&'static [&'static (KeyButMask,KeyButMas
 		  &(NONE,NONE,"\x1b[4~")],
     "KP_Next" => &[&(SHIFT, FIX, "\x1b[6;2~"),
 		   &(NONE,NONE,"\x1b[6~")],
-    "KP_Insert" => &[&(SHIFT, FIX, "\x1b[4l"),
-		     &(CONTROL, FIX, "\x1b[L"),
+    "KP_Insert" => &[&(CONTROL, FIX, "\x1b[L"),
 		     &(NONE,NONE,"\x1b[4h")],
     "KP_Delete" => &[&(CONTROL, FIX, "\x1b[M"),
 		     &(SHIFT, FIX, "\x1b[2K"),
@@ -121,8 +383,7 @@ static SHORTCUTS: phf::Map<&'static str,&'static [&'static (KeyButMask,KeyButMas
     "Tab" => &[&(KeyButMask::SHIFT,FIX,"\x1b[Z")],
     "Return" => &[&(KeyButMask::MOD1,FIX,"\x1b\n"), // \r in st
 		  &(NONE,NONE,"\n")],  // \r in st
-    "Insert" => &[&(KeyButMask::SHIFT, FIX, "\x1b[4l"),
-		  &(KeyButMask::CONTROL, FIX, "\x1b[L"),
+    "Insert" => &[&(KeyButMask::CONTROL, FIX, "\x1b[L"),
 		  &(NONE,NONE,"\x1b[4h")],
     "Delete" => &[&(KeyButMask::CONTROL, FIX, "\x1b[M"),
 		  &(KeyButMask::SHIFT, FIX, "\x1b[2K"),
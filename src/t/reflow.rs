@@ -0,0 +1,144 @@
+// soft-wrapped lines of the scrollback and the page rewrapped when the line width changes
+use std::cmp;
+use super::State;
+use super::state::QueueView;
+use super::write::WIDE_CELL;
+
+// rows of at most width cells, a wide character that would be cut goes to the next row
+fn split(line: &[u8], width: usize) -> Vec<Vec<u8>> {
+    let mut rows = Vec::new();
+    let mut rest = line;
+    while rest.len() > width {
+	let n = if rest[width] == WIDE_CELL && width > 1 {width-1} else {width};
+	rows.push(rest[..n].to_vec());
+	rest = &rest[n..];
+    }
+    rows.push(rest.to_vec());
+    rows
+}
+
+impl<T: FnMut(Option<usize>)> State<'_, T> {
+    // after line_width changed from old, the cursor stays on the same character of its logical line
+    pub(super) fn reflow(&mut self, old: usize) {
+	let width = self.config.line_width as usize;
+	if width == old {return};
+	let (cur, page) = (self.lines.cur(), self.lines.len());
+	let rows: Vec<_> = (0..page)
+	    .map(|row| (std::mem::take(&mut self.lines[row]), self.wrapped.contains(&row), Some(row)))
+	    .collect();
+	let scrollback = self.scrollback.take().into_iter().map(|(line, wrapped)| (line, wrapped, None));
+	// rows joined into logical lines, wrapped ones filled up to the old width shown as their trailing spaces were cut,
+	// one short if a wide character that did not fit went to the next row
+	let mut logical: Vec<Vec<u8>> = Vec::new();
+	let mut starts = Vec::new();   // page rows that begin a logical line, with its index
+	let (mut open, mut first, mut cursor) = (false, 0, (0, 0));
+	let mut all = scrollback.chain(rows).peekable();
+	while let Some((mut line, wrapped, row)) = all.next() {
+	    if !open {
+		logical.push(Vec::new());
+		if let Some(row) = row {starts.push((row, logical.len()-1))};
+	    }
+	    let i = logical.len()-1;
+	    if row == Some(0) {first = i};
+	    if row == Some(cur) {cursor = (i, logical[i].len() + self.col)};
+	    let shown = if row.is_some_and(|row| self.line_size(row).is_some()) {cmp::max(old/2, 1)} else {old};
+	    let end = match all.peek() {
+		Some((next, _, _)) if next.get(1) == Some(&WIDE_CELL) => shown-1,
+		_ => shown,
+	    };
+	    if wrapped && line.len() < end {line.resize(end, b' ')};
+	    logical[i].extend_from_slice(&line);
+	    open = wrapped;
+	}
+	// split again at the new width
+	let mut rows: Vec<(Vec<u8>, bool)> = Vec::new();
+	let mut first_row = Vec::with_capacity(logical.len());
+	let mut cursor_at = (0, 0);
+	for (i, line) in logical.iter().enumerate() {
+	    first_row.push(rows.len());
+	    let parts = split(line, width);
+	    if i == cursor.0 {
+		let (mut k, mut col) = (0, cursor.1);
+		while k+1 < parts.len() && col >= parts[k].len() {
+		    col -= parts[k].len();
+		    k += 1;
+		}
+		cursor_at = (rows.len()+k, col);
+	    }
+	    let n = parts.len();
+	    rows.extend(parts.into_iter().enumerate().map(|(k, part)| (part, k+1 < n)));
+	}
+	// the page starts where it did unless that leaves too many rows or the cursor above it
+	let max = self.lines.max;
+	let top = cmp::min(cmp::max(first_row[first], rows.len().saturating_sub(max)), cursor_at.0);
+	let len = cmp::min(rows.len()-top, max);
+	let mut rows = rows.into_iter();
+	for (line, wrapped) in rows.by_ref().take(top) {self.scrollback.push(line, wrapped)};
+	self.lines = QueueView::<Vec<u8>>::new(max as u16);
+	self.wrapped.clear();
+	for (row, (line, wrapped)) in rows.take(len).enumerate() {
+	    if row > 0 {self.lines.add(Vec::new());}
+	    self.lines[row] = line;
+	    if wrapped && row+1 < len {self.wrapped.push(row)};
+	}
+	let moved = |row: &mut usize| match starts.iter().find(|(r, _)| r == row) {
+	    Some((_, i)) if first_row[*i] >= top && first_row[*i] < top+len => {
+		*row = first_row[*i]-top;
+		true
+	    },
+	    _ => false,
+	};
+	self.images.retain_mut(|p| moved(&mut p.row));
+	self.line_sizes.retain_mut(|(row, _)| moved(row));
+	self.end_copy();   // history lines are no longer where they were
+	self.selection = None;
+	self.drag = None;
+	self.lines.start = 0;
+	self.lines.end = len;
+	self.lines.set_cur(&mut None::<fn(Option<usize>)>, cursor_at.0-top);   // drawn by the caller
+	self.col = cursor_at.1;
+    }
+}
+
+#[cfg(test)]
+mod reflow_tests {
+    use super::*;
+    use crate::t::state_test::*;
+    #[test]
+    fn reflow() {
+	let mut config = Config::new();
+	(config.line_width, config.n_lines, config.window_ops) = (10, 3, true);
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all(b"abcdefghijklmnop\r\nxyz").unwrap();
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], state.wrapped.clone()), (&b"abcdefghij"[..], &b"klmnop"[..], vec![0]));
+	state.write_all(b"\x1b[2;3H\x1b[8;;4t").unwrap();   // on the m
+	assert_eq!((state.scrollback.len(), &state.scrollback[0][..], &state.scrollback[1][..]), (2, &b"abcd"[..], &b"efgh"[..]));
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], &state.lines[2][..]), (&b"ijkl"[..], &b"mnop"[..], &b"xyz"[..]));
+	assert_eq!((state.lines.cur(), state.col, state.wrapped.clone()), (1, 0, vec![0]));
+	state.write_all(b"\x1b[8;;20t").unwrap();
+	assert_eq!((state.scrollback.len(), &state.lines[0][..], &state.lines[1][..]), (0, &b"abcdefghijklmnop"[..], &b"xyz"[..]));
+	assert_eq!((state.lines.cur(), state.col), (0, 12));
+    }
+    #[test]
+    fn wide() {
+	let mut config = Config::new();
+	(config.line_width, config.n_lines, config.window_ops) = (5, 3, true);
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all("abcd漢字".as_bytes()).unwrap();
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], state.col), (&b"abcd"[..], &b"?\x01?\x01"[..], 4));
+	state.write_all(b"\x1b[8;;3t").unwrap();
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], &state.lines[2][..]), (&b"abc"[..], &b"d?\x01"[..], &b"?\x01"[..]));
+	assert_eq!((state.scrollback.len(), state.lines.cur(), state.col), (0, 2, 2));
+	state.write_all(b"\x1b[8;;5t").unwrap();
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], state.col), (&b"abcd"[..], &b"?\x01?\x01"[..], 4));    }
+    #[test]
+    fn double_width() {
+	let mut config = Config::new();
+	(config.line_width, config.n_lines, config.window_ops) = (10, 3, true);
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all(b"\x1b#6abc   de").unwrap();   // 5 cells shown
+	assert_eq!((&state.lines[0][..], &state.lines[1][..], state.wrapped.clone()), (&b"abc"[..], &b" de"[..], vec![0]));
+	state.write_all(b"\x1b[8;;20t").unwrap();
+	assert_eq!((&state.lines[0][..], state.lines.len(), state.col), (&b"abc   de"[..], 1, 8));
+    }
+}
@@ -0,0 +1,174 @@
+// incremental search through the scrollback and the page, Window draws the prompt over the last line
+use std::cmp;
+use regex_lite::Regex;
+use xcb::x::KeyButMask;
+use xkbcommon::xkb;
+use super::State;
+
+pub struct Search {
+    pub pattern: String,
+    pub regex: bool,   // pattern is a regular expression, literal text otherwise
+    compiled: Option<Regex>,   // None for an empty or invalid pattern
+    current: Option<(usize, usize, usize)>,   // history line, start and end column of the match shown
+}
+pub enum SearchKey {
+    Char(char),
+    Backspace,
+    Older,
+    Newer,
+    ToggleRegex,
+    Close,
+}
+impl Search {
+    fn new() -> Search {
+	Search {
+	    pattern: String::new(),
+	    regex: false,
+	    compiled: None,
+	    current: None,
+	}
+    }
+    fn compile(&mut self) {
+	self.compiled = if self.pattern.is_empty() {
+	    None
+	} else if self.regex {
+	    Regex::new(&self.pattern).ok()
+	} else {
+	    Regex::new(&regex_lite::escape(&self.pattern)).ok()
+	};
+    }
+    // start and end columns, cells are ASCII apart from the image and wide character markers
+    pub fn matches(&self, line: &[u8]) -> Vec<(usize, usize)> {
+	let Some(re) = &self.compiled else {return Vec::new()};
+	let text = String::from_utf8_lossy(line);
+	re.find_iter(&text).filter(|m| !m.is_empty()).map(|m| (m.start(), m.end())).collect()
+    }
+}
+impl SearchKey {
+    // Ctrl+Shift+F starts a search
+    pub fn starts(key_sym: xkb::Keysym, mask: KeyButMask) -> bool {
+	mask & MODS == KeyButMask::SHIFT | KeyButMask::CONTROL && xkb::keysym_get_name(key_sym) == "F"
+    }
+    // Enter and Up go to older matches, Shift+Enter and Down to newer ones, Ctrl+R switches to regular expressions
+    pub fn from_key(key_sym: xkb::Keysym, mask: KeyButMask, key_sym_mod: xkb::Keysym) -> Option<SearchKey> {
+	let shift = mask & MODS == KeyButMask::SHIFT;
+	match &xkb::keysym_get_name(key_sym)[..] {
+	    "Escape" => Some(SearchKey::Close),
+	    "BackSpace" => Some(SearchKey::Backspace),
+	    "Return" | "KP_Enter" if shift => Some(SearchKey::Newer),
+	    "Return" | "KP_Enter" | "Up" | "Prior" => Some(SearchKey::Older),
+	    "Down" | "Next" => Some(SearchKey::Newer),
+	    "r" if mask & MODS == KeyButMask::CONTROL => Some(SearchKey::ToggleRegex),
+	    _ if mask.intersects(KeyButMask::CONTROL | KeyButMask::MOD1) => None,
+	    _ => xkb::keysym_to_utf8(key_sym_mod).chars().next().filter(|c| !c.is_control()).map(SearchKey::Char),
+	}
+    }
+}
+const MODS: KeyButMask = KeyButMask::from_bits_truncate(
+    KeyButMask::SHIFT.bits() | KeyButMask::CONTROL.bits() | KeyButMask::MOD1.bits());
+
+impl<T: FnMut(Option<usize>)> State<'_, T> {
+    pub fn start_search(&mut self) {
+	self.search = Some(Search::new());
+	self.draw(None);
+    }
+    pub fn searching(&self) -> bool {
+	self.search.is_some()
+    }
+    pub fn search_key(&mut self, key: SearchKey) {
+	let Some(search) = &mut self.search else {return};
+	match key {
+	    SearchKey::Char(c) => {
+		search.pattern.push(c);
+		search.compile();
+		self.search_find(true, true);
+	    },
+	    SearchKey::Backspace => {
+		search.pattern.pop();
+		search.compile();
+		self.search_find(true, true);
+	    },
+	    SearchKey::ToggleRegex => {
+		search.regex = !search.regex;
+		search.compile();
+		self.search_find(true, true);
+	    },
+	    SearchKey::Older => self.search_find(true, false),
+	    SearchKey::Newer => self.search_find(false, false),
+	    SearchKey::Close => self.search = None,
+	}
+	self.draw(None);
+    }
+    // text of the prompt, None if there is no search
+    pub fn search_prompt(&self) -> Option<String> {
+	let search = self.search.as_ref()?;
+	let status = if search.pattern.is_empty() {""}
+	else if search.compiled.is_none() {" (invalid)"}
+	else if search.current.is_none() {" (not found)"}
+	else {""};
+	Some(format!("{}: {}{}", if search.regex {"regex"} else {"search"}, search.pattern, status))
+    }
+    // matches in row n of the window, true for the current one
+    pub fn search_marks(&self, n: usize) -> Vec<(usize, usize, bool)> {
+	let Some(search) = &self.search else {return Vec::new()};
+	let i = self.scrollback.len() + n - self.scrollback.offset;
+	search.matches(self.history_line(i)).into_iter()
+	    .map(|(s, e)| (s, e, search.current == Some((i, s, e))))
+	    .collect()
+    }
+    // next match before or after the current one, or at it if the pattern changed
+    fn search_find(&mut self, older: bool, changed: bool) {
+	let Some(search) = &self.search else {return};
+	let len = self.history_len();
+	let (line, col) = search.current.map_or((len, 0), |(i, s, _)| (i, s));
+	let found = if older {
+	    (0..cmp::min(line+1, len)).rev().find_map(|i| {
+		search.matches(self.history_line(i)).into_iter().rev()
+		    .find(|&(s, _)| i < line || s < col || (changed && s == col))
+		    .map(|(s, e)| (i, s, e))
+	    })
+	} else {
+	    (line..len).find_map(|i| {
+		search.matches(self.history_line(i)).into_iter()
+		    .find(|&(s, _)| i > line || s > col || (changed && s == col))
+		    .map(|(s, e)| (i, s, e))
+	    })
+	};
+	let current = if found.is_none() && !changed {search.current} else {found};
+	if let Some(search) = &mut self.search {search.current = current};
+	if let Some((i, _, _)) = current {self.show_history_line(i)};
+    }
+}
+
+#[cfg(test)]
+mod search_tests {
+    use super::*;
+    use crate::t::state_test::*;
+    #[test]
+    fn search() {
+	let mut config = Config::new();
+	config.n_lines = 2;
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::with_config(config);
+	state.write_all(b"ab1 ab2\ncd\nab3\nx").unwrap();
+	state.start_search();
+	for c in "ab".chars() {state.search_key(SearchKey::Char(c))};
+	assert_eq!((state.view_offset(), state.search_marks(0)), (0, vec![(0, 2, true)]));
+	state.search_key(SearchKey::Older);
+	assert_eq!((state.view_offset(), state.search_marks(0)), (2, vec![(0, 2, false), (4, 6, true)]));
+	state.search_key(SearchKey::Older);
+	state.search_key(SearchKey::Older);
+	assert_eq!(state.search_marks(0), vec![(0, 2, true), (4, 6, false)]);
+	state.search_key(SearchKey::Newer);
+	assert_eq!(state.search_marks(0), vec![(0, 2, false), (4, 6, true)]);
+	state.search_key(SearchKey::ToggleRegex);
+	state.search_key(SearchKey::Char('['));
+	assert_eq!(state.search_prompt().as_deref(), Some("regex: ab[ (invalid)"));
+	state.search_key(SearchKey::Backspace);
+	for c in "[13]".chars() {state.search_key(SearchKey::Char(c))};
+	assert_eq!((state.view_offset(), state.search_marks(1)), (1, vec![(0, 3, true)]));
+	state.search_key(SearchKey::Char('x'));
+	assert_eq!(state.search_prompt().as_deref(), Some("regex: ab[13]x (not found)"));
+	state.search_key(SearchKey::Close);
+	assert!(!state.searching() && state.search_marks(0).is_empty());
+    }
+}
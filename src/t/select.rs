@@ -0,0 +1,197 @@
+// part of the history (scrollback and page) selected to be copied
+use std::cmp;
+use xcb::x::KeyButMask;
+use xkbcommon::xkb;
+use super::State;
+use super::write::{IMAGE_CELL,WIDE_CELL};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SelectKind {
+    Char,
+    Word,
+    Line,
+    Block,
+}
+// X selection the text goes to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Clip {
+    Primary,
+    Clipboard,
+}
+impl Clip {
+    // the selection a key pastes, Shift+Insert PRIMARY and Ctrl+Shift+V CLIPBOARD (PRIMARY if nobody owns it)
+    pub fn from_key(key_sym: xkb::Keysym, mask: KeyButMask) -> Option<Clip> {
+	let mods = mask & (KeyButMask::SHIFT | KeyButMask::CONTROL | KeyButMask::MOD1);
+	match &xkb::keysym_get_name(key_sym)[..] {
+	    "Insert" | "KP_Insert" if mods == KeyButMask::SHIFT => Some(Clip::Primary),
+	    "V" if mods == KeyButMask::SHIFT | KeyButMask::CONTROL => Some(Clip::Clipboard),
+	    _ => None,
+	}
+    }
+}
+#[derive(Clone, Copy)]
+pub struct Selection {
+    pub kind: SelectKind,
+    pub anchor: (usize, usize),   // history line and column where it started
+    pub head: (usize, usize),   // the end that moves
+}
+impl Selection {
+    pub fn new(kind: SelectKind, at: (usize, usize)) -> Selection {
+	Selection {kind, anchor: at, head: at}
+    }
+    // first and last history line
+    pub fn lines(&self) -> (usize, usize) {
+	(cmp::min(self.anchor.0, self.head.0), cmp::max(self.anchor.0, self.head.0))
+    }
+    // columns of history line i covered, the end excluded, usize::MAX for the rest of the line
+    // words are extended by State::selected first
+    pub fn columns(&self, i: usize) -> Option<(usize, usize)> {
+	let (first, last) = if self.anchor <= self.head {(self.anchor, self.head)} else {(self.head, self.anchor)};
+	if i < first.0 || i > last.0 {return None};
+	Some(match self.kind {
+	    SelectKind::Line => (0, usize::MAX),
+	    SelectKind::Block => {
+		let (a, b) = (self.anchor.1, self.head.1);
+		(cmp::min(a, b), cmp::max(a, b)+1)
+	    },
+	    SelectKind::Char | SelectKind::Word => (
+		if i == first.0 {first.1} else {0},
+		if i == last.0 {last.1+1} else {usize::MAX},
+	    ),
+	})
+    }
+}
+
+impl<T: FnMut(Option<usize>)> State<'_, T> {
+    // selected text, lines without trailing spaces joined by newlines, other than ASCII shown as '?'
+    pub fn selection_text(&self) -> Vec<u8> {
+	let Some(sel) = self.selected() else {return Vec::new()};
+	let (first, last) = sel.lines();
+	let mut text = Vec::new();
+	for i in first..cmp::min(last+1, self.history_len()) {
+	    let Some((s, e)) = sel.columns(i) else {continue};
+	    let line = self.history_line(i);
+	    let cells = &line[cmp::min(s, line.len())..cmp::min(e, line.len())];
+	    let start = text.len();
+	    text.extend(cells.iter().filter(|c| **c != WIDE_CELL).map(|c| if *c == IMAGE_CELL {b' '} else {*c}));
+	    while text.len() > start && text.last() == Some(&b' ') {text.pop();}
+	    if i < last {text.push(b'\n')};
+	}
+	text
+    }
+    // selected columns in row n of the window
+    pub fn selection_marks(&self, n: usize) -> Option<(usize, usize)> {
+	let i = (self.scrollback.len() + n).checked_sub(self.scrollback.offset)?;
+	self.selected()?.columns(i)
+    }
+    // the selection with word ends moved to the word boundaries
+    fn selected(&self) -> Option<Selection> {
+	let sel = self.selection?;
+	if sel.kind != SelectKind::Word {return Some(sel)};
+	let (first, last) = if sel.anchor <= sel.head {(sel.anchor, sel.head)} else {(sel.head, sel.anchor)};
+	let (start, end) = (self.word_at(first).0, self.word_at(last).1);
+	Some(Selection {kind: SelectKind::Char, anchor: (first.0, start), head: (last.0, end.saturating_sub(1))})
+    }
+    // columns of the word around a history line and column, just that cell if it is no word character
+    fn word_at(&self, (i, c): (usize, usize)) -> (usize, usize) {
+	let line = self.history_line(i);
+	let word = |b: u8| b.is_ascii_alphanumeric() || b == b'?' || b == WIDE_CELL
+	    || self.config.word_chars.as_bytes().contains(&b);
+	if c >= line.len() || !word(line[c]) {return (c, c+1)};
+	let start = line[..c].iter().rposition(|b| !word(*b)).map_or(0, |s| s+1);
+	let end = line[c..].iter().position(|b| !word(*b)).map_or(line.len(), |e| c+e);
+	(start, end)
+    }
+    // button 1 pressed at row and column of the window, the second click selects a word, the third the line
+    pub fn mouse_press(&mut self, row: usize, col: usize, clicks: u8, block: bool) {
+	let Some(at) = self.history_at(row, col) else {return};
+	self.clear_selection();
+	let kind = match clicks {
+	    _ if block => SelectKind::Block,
+	    1 => SelectKind::Char,
+	    2 => SelectKind::Word,
+	    _ => SelectKind::Line,
+	};
+	if kind == SelectKind::Char || kind == SelectKind::Block {   // nothing selected until the mouse moves
+	    self.drag = Some((kind, at));
+	} else {
+	    self.drag = None;
+	    self.selection = Some(Selection::new(kind, at));
+	    self.draw(None);
+	}
+    }
+    pub fn mouse_drag(&mut self, row: usize, col: usize) {
+	let Some(at) = self.history_at(row, col) else {return};
+	if let Some((kind, anchor)) = self.drag.take() {
+	    self.selection = Some(Selection::new(kind, anchor));
+	}
+	let Some(sel) = &mut self.selection else {return};
+	sel.head = at;
+	self.draw(None);
+    }
+    // text for PRIMARY once the button is released
+    pub fn mouse_release(&mut self) -> Option<Vec<u8>> {
+	self.drag = None;
+	self.selection.map(|_| self.selection_text())
+    }
+    fn history_at(&self, row: usize, col: usize) -> Option<(usize, usize)> {
+	if self.copy.is_some() || row >= self.lines.vis_len() {return None};
+	let i = (self.scrollback.len() + row).checked_sub(self.scrollback.offset)?;
+	Some((i, col))
+    }
+    pub fn clear_selection(&mut self) {
+	if self.selection.take().is_some() {self.draw(None)};
+    }
+}
+
+#[cfg(test)]
+mod select_tests {
+    use super::*;
+    use crate::t::state_test::*;
+    use xkbcommon::xkb::keysyms::*;
+    #[test]
+    fn keys() {
+	let (shift, ctrl) = (KeyButMask::SHIFT, KeyButMask::CONTROL);
+	assert_eq!(Clip::from_key(KEY_Insert, shift), Some(Clip::Primary));
+	assert_eq!(Clip::from_key(KEY_KP_Insert, shift | KeyButMask::LOCK), Some(Clip::Primary));
+	assert_eq!(Clip::from_key(KEY_V, shift | ctrl), Some(Clip::Clipboard));
+	assert_eq!(Clip::from_key(KEY_Insert, shift | ctrl), None);
+	assert_eq!(Clip::from_key(KEY_v, ctrl), None);
+    }
+    #[test]
+    fn text() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.write_all("abc  \nde漢fg\nhi".as_bytes()).unwrap();
+	state.selection = Some(Selection::new(SelectKind::Char, (0, 1)));
+	state.selection.as_mut().unwrap().head = (1, 3);
+	assert_eq!(state.selection_text(), b"bc\nde?");
+	assert_eq!(state.selection_marks(1), Some((0, 4)));
+	state.selection.as_mut().unwrap().kind = SelectKind::Block;
+	assert_eq!(state.selection_text(), b"bc\ne?");
+	state.selection.as_mut().unwrap().kind = SelectKind::Line;
+	state.selection.as_mut().unwrap().head = (2, 0);
+	assert_eq!(state.selection_text(), b"abc\nde?fg\nhi");
+	assert_eq!(state.selection_marks(3), None);
+    }
+    #[test]
+    fn mouse() {
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.write_all(b"ls -l /tmp/a.b; echo\nsecond line").unwrap();
+	state.mouse_press(0, 8, 2, false);
+	assert_eq!(state.mouse_release(), Some(b"/tmp/a.b".to_vec()));
+	state.mouse_press(0, 14, 2, false);
+	assert_eq!(state.selection_marks(0), Some((14, 15)));
+	state.mouse_drag(1, 2);
+	assert_eq!(state.mouse_release(), Some(b"; echo\nsecond".to_vec()));
+	state.mouse_press(0, 3, 1, false);
+	assert_eq!((state.selection_marks(0), state.mouse_release()), (None, None));
+	state.mouse_press(0, 3, 1, false);
+	state.mouse_drag(0, 1);
+	assert_eq!(state.mouse_release(), Some(b"s -".to_vec()));
+	state.mouse_press(1, 1, 1, true);
+	state.mouse_drag(0, 2);
+	assert_eq!(state.mouse_release(), Some(b"s\nec".to_vec()));
+	state.mouse_press(1, 0, 3, false);
+	assert_eq!(state.mouse_release(), Some(b"second line".to_vec()));
+    }
+}
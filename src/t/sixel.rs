@@ -0,0 +1,208 @@
+// sixel graphics, see https://vt100.net/docs/vt3xx-gp/chapter14.html
+use super::color::Rgb;
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgba: Vec<u8>,
+}
+// image with its top left corner at a cell, row as in QueueView
+pub(super) struct Placement {
+    pub row: usize,
+    pub col: usize,
+    pub rows: usize,   // text lines covered
+    pub image: Image,
+}
+
+const TRANSPARENT: u32 = 0;
+const OPAQUE: u32 = 0xff000000;
+
+pub(super) struct Sixel {
+    palette: Vec<Rgb>,
+    color: usize,
+    x: usize,
+    y: usize,
+    width: usize,    // size of pixels, grows as needed
+    height: usize,
+    used: (usize, usize),   // size actually drawn or set with raster attributes
+    pixels: Vec<u32>,       // OPAQUE | rgb or TRANSPARENT
+    background: Option<Rgb>,   // fill for pixels that are not drawn, None: transparent
+    max_pixels: usize,
+    cmd: Option<u8>,       // '#', '!' or '"' waiting for its parameters
+    params: [usize; 5],
+    n_params: usize,
+    repeat: usize,
+}
+impl Sixel {
+    // from DCS P1 ; P2 ; P3 q, P2 = 1 keeps undrawn pixels transparent
+    pub fn new(p2: Option<u16>, palette: Vec<Rgb>, background: Rgb, max_bytes: usize) -> Sixel {
+	Sixel {
+	    palette: palette,
+	    color: 0,
+	    x: 0,
+	    y: 0,
+	    width: 0,
+	    height: 0,
+	    used: (0, 0),
+	    pixels: Vec::new(),
+	    background: if p2 == Some(1) {None} else {Some(background)},
+	    max_pixels: max_bytes/4,
+	    cmd: None,
+	    params: [0; 5],
+	    n_params: 0,
+	    repeat: 0,
+	}
+    }
+    pub fn put(&mut self, data: &[u8]) {
+	for b in data {
+	    match (self.cmd, *b) {
+		(Some(_), b'0'..=b'9') => {
+		    if self.n_params == 0 {self.n_params = 1};
+		    let p = &mut self.params[self.n_params-1];
+		    *p = p.saturating_mul(10).saturating_add((b-b'0') as usize);
+		},
+		(Some(_), b';') => if self.n_params < self.params.len() {
+		    if self.n_params == 0 {self.n_params = 1};
+		    self.params[self.n_params] = 0;
+		    self.n_params += 1;
+		},
+		(_, b) => {
+		    self.end_cmd();
+		    match b {
+			b'#' | b'!' | b'"' => {
+			    self.cmd = Some(b);
+			    self.params = [0; 5];
+			    self.n_params = 0;
+			},
+			b'$' => self.x = 0,
+			b'-' => {
+			    self.x = 0;
+			    self.y += 6;
+			},
+			0x3f..=0x7e => self.sixel(b-0x3f),
+			_ => (),
+		    }
+		},
+	    }
+	}
+    }
+    fn end_cmd(&mut self) {
+	let p = self.params;
+	match (self.cmd.take(), self.n_params) {
+	    (Some(b'!'), _) => self.repeat = p[0],
+	    (Some(b'#'), 0 | 1) => self.color = p[0] % self.palette.len(),
+	    (Some(b'#'), _) => {
+		let rgb = match p[1] {
+		    1 => hls(p[2], p[3], p[4]),
+		    2 => {
+			let c = |v: usize| (v.min(100)*255/100) as Rgb;
+			c(p[2]) << 16 | c(p[3]) << 8 | c(p[4])
+		    },
+		    _ => return,
+		};
+		self.color = p[0] % self.palette.len();
+		self.palette[self.color] = rgb;
+	    },
+	    (Some(b'"'), 4..) => {   // Pan ; Pad ; Ph ; Pv, only the size is used
+		if self.grow(p[2], p[3]) {
+		    self.used = (self.used.0.max(p[2]), self.used.1.max(p[3]));
+		}
+	    },
+	    _ => (),
+	}
+    }
+    fn sixel(&mut self, bits: u8) {
+	let n = self.repeat.max(1);
+	self.repeat = 0;
+	let (x, y) = (self.x, self.y);
+	self.x = self.x.saturating_add(n);
+	if bits == 0 || !self.grow(x.saturating_add(n), y+6) {return};
+	let color = OPAQUE | self.palette[self.color];
+	for i in 0..6 {
+	    if bits & 1 << i != 0 {
+		let row = (y+i)*self.width;
+		self.pixels[row+x..row+x+n].fill(color);
+		self.used.1 = self.used.1.max(y+i+1);
+	    }
+	}
+	self.used.0 = self.used.0.max(x+n);
+    }
+    // false if the image would get too big
+    fn grow(&mut self, width: usize, height: usize) -> bool {
+	if width <= self.width && height <= self.height {return true};
+	let exact = (width.max(self.width), height.max(self.height));
+	if exact.0.saturating_mul(exact.1) > self.max_pixels {return false};
+	// doubling avoids copying everything for each sixel, unless that exceeds the limit
+	let double = (if width > self.width {exact.0.max(2*self.width).max(64)} else {self.width},
+		      if height > self.height {exact.1.max(2*self.height)} else {self.height});
+	let (w, h) = if double.0.saturating_mul(double.1) <= self.max_pixels {double} else {exact};
+	let mut pixels = vec![TRANSPARENT; w*h];
+	for row in 0..self.height {
+	    pixels[row*w..row*w+self.width].copy_from_slice(&self.pixels[row*self.width..(row+1)*self.width]);
+	}
+	self.pixels = pixels;
+	(self.width, self.height) = (w, h);
+	true
+    }
+    pub fn finish(mut self) -> Option<Image> {
+	self.end_cmd();
+	let (width, height) = self.used;
+	if width == 0 || height == 0 {return None};
+	let mut rgba = Vec::with_capacity(width*height*4);
+	for row in 0..height {
+	    for p in &self.pixels[row*self.width..row*self.width+width] {
+		let p = match (*p, self.background) {
+		    (TRANSPARENT, Some(bg)) => OPAQUE | bg,
+		    (p, _) => p,
+		};
+		rgba.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8, (p >> 24) as u8]);
+	    }
+	}
+	Some(Image {width, height, rgba})
+    }
+}
+// hue 0 is blue for sixel, lightness and saturation in percent
+fn hls(h: usize, l: usize, s: usize) -> Rgb {
+    let (h, l, s) = (((h+240)%360) as f32, l.min(100) as f32/100.0, s.min(100) as f32/100.0);
+    let c = (1.0-(2.0*l-1.0).abs())*s;
+    let x = c*(1.0-((h/60.0)%2.0-1.0).abs());
+    let (r, g, b) = match h as u32/60 {
+	0 => (c, x, 0.0),
+	1 => (x, c, 0.0),
+	2 => (0.0, c, x),
+	3 => (0.0, x, c),
+	4 => (x, 0.0, c),
+	_ => (c, 0.0, x),
+    };
+    let v = |v: f32| ((v+l-c/2.0)*255.0).round() as Rgb;
+    v(r) << 16 | v(g) << 8 | v(b)
+}
+
+#[cfg(test)]
+mod sixel_tests {
+    use super::*;
+    fn decode(data: &[u8], p2: Option<u16>, max_bytes: usize) -> Option<Image> {
+	let mut sixel = Sixel::new(p2, vec![0x000000; 16], 0xffffff, max_bytes);
+	sixel.put(data);
+	sixel.finish()
+    }
+    #[test]
+    fn decode_image() {
+	// red column of 6 pixels, then 2 blue ones in the first row of a second band via repeat
+	let image = decode(b"#1;2;100;0;0#2;2;0;0;100#1~-#2!2@", Some(1), 1 << 20).unwrap();
+	assert_eq!((image.width, image.height), (2, 7));
+	assert_eq!(&image.rgba[0..8], &[255, 0, 0, 255, 0, 0, 0, 0]);
+	assert_eq!(&image.rgba[6*8..7*8], &[0, 0, 255, 255, 0, 0, 255, 255]);
+	let image = decode(b"\"1;1;4;6#0~$#3;1;120;50;100@", None, 1 << 20).unwrap();
+	assert_eq!((image.width, image.height), (4, 6));
+	assert_eq!(&image.rgba[0..4], &[255, 0, 0, 255]);
+	assert_eq!(&image.rgba[4..8], &[255, 255, 255, 255]);
+    }
+    #[test]
+    fn limits() {
+	assert!(decode(b"!100~", None, 100*6*4-1).is_none());
+	assert!(decode(b"!100~", None, 100*6*4).is_some());
+	assert!(decode(b"!99999999999999999999~\"99999999;99999999;99999999;9999999", None, 1 << 20).is_none());
+	assert!(decode(b"-$-", None, 1 << 20).is_none());
+    }
+}
@@ -1,7 +1,11 @@
 use std::cmp;
+use std::collections::VecDeque;
 use std::iter::once;
 use std::io::{Read,Result};
+use std::time::{Duration,Instant};
 use super::State;
+use super::trace::TraceTarget;
+use super::color::{Rgb,ANSI_COLORS};
 
 pub(super) struct QueueView<T: Empty>{
     vec: Vec<T>,
@@ -22,6 +26,16 @@ impl QueueView<Vec<u8>> {
 	    cur: 0,
 	}
     }
+    // like add(Vec::new()) but reuses the buffer of the line that drops out
+    pub(super) fn add_empty(&mut self) -> usize {
+	if self.vec.len() < self.max {
+	    self.add(Vec::new())
+	} else {
+	    self.vec[self.ofs].clear();
+	    self.inc_ofs(1);
+	    0
+	}
+    }
 }
 impl<T: Empty> std::ops::Index<usize> for QueueView<T> {
     type Output = T;
@@ -81,6 +95,10 @@ impl<T: Empty> QueueView<T> {
 	    0
 	}
     }
+    pub(super) fn swap(&mut self, i: usize, j: usize) {
+	let l = self.len();
+	self.vec.swap((i+self.ofs)%l, (j+self.ofs)%l);
+    }
     pub(super) fn len(&self) -> usize {
 	self.vec.len()
     }
@@ -103,6 +121,57 @@ impl<T> Empty for Vec<T> {
 	self.is_empty()
     }
 }
+// lines that scrolled off the top of the page, oldest first, true if soft-wrapped into the next one
+pub(super) struct Scrollback {
+    lines: VecDeque<(Vec<u8>, bool)>,
+    pub max: usize,
+    pub offset: usize,   // lines the view is scrolled back, 0 follows the output
+    pub dropped: usize,   // lines removed from the front so far, history indices move back by as many
+}
+impl Scrollback {
+    pub(super) fn new(max: usize) -> Scrollback {
+	Scrollback {
+	    lines: VecDeque::new(),
+	    max,
+	    offset: 0,
+	    dropped: 0,
+	}
+    }
+    pub(super) fn push(&mut self, line: Vec<u8>, wrapped: bool) {
+	if self.max == 0 {return};
+	if self.lines.len() >= self.max {
+	    self.lines.pop_front();
+	    self.dropped += 1;
+	}
+	self.lines.push_back((line, wrapped));
+    }
+    // all lines with their flags, the view back at the output
+    pub(super) fn take(&mut self) -> VecDeque<(Vec<u8>, bool)> {
+	self.offset = 0;
+	std::mem::take(&mut self.lines)
+    }
+    pub(super) fn clear(&mut self) {
+	self.dropped += self.lines.len();
+	self.lines.clear();
+	self.offset = 0;
+    }
+    pub(super) fn len(&self) -> usize {
+	self.lines.len()
+    }
+    // back if n is positive, true if the view moved
+    pub(super) fn scroll(&mut self, n: isize) -> bool {
+	let offset = cmp::min(self.offset.saturating_add_signed(n), self.len());
+	let moved = offset != self.offset;
+	self.offset = offset;
+	moved
+    }
+}
+impl std::ops::Index<usize> for Scrollback {
+    type Output = Vec<u8>;
+    fn index(&self, i: usize) -> &Vec<u8> {
+	&self.lines[i].0
+    }
+}
 
 
 
@@ -186,12 +255,40 @@ pub struct WindowPosition {
     pub x_offset: u16,
     pub y_offset: u16,
 }
+impl WindowPosition {
+    // top left corner of a window of width x height pixels on a screen of the given size
+    pub(super) fn get_xy(&self, width: u16, height: u16, screen: (u16, u16)) -> (i32,i32) {
+	(
+	    if self.right {
+		screen.0 as i32 - self.x_offset as i32 - width as i32
+	    } else { self.x_offset as i32 },
+	    if self.bottom {
+		screen.1 as i32 - self.y_offset as i32 - height as i32
+	    } else { self.y_offset as i32 }
+	)
+    }
+}
 pub struct Config {
-    pub dark: bool,
+    pub dark: bool,   // swaps foreground and background
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub cursor_color: Option<Rgb>,   // None: foreground
+    pub palette: [Rgb; 16],
     pub line_width: u16,
     pub n_lines: u16,
     pub pos: WindowPosition,
     pub last_line_border: bool,
+    pub sync_timeout: Duration,
+    pub cursor_shape: CursorShape,
+    pub cursor_blink: bool,
+    pub blink_interval: Duration,
+    pub image_memory: usize,   // bytes for all sixel images together, the oldest are dropped
+    pub scrollback: usize,   // lines kept after they scroll off the top, 0 for none
+    pub word_chars: String,   // taken as part of a word by a double click besides letters and digits
+    pub ambiguous_wide: bool,   // East Asian ambiguous width characters like Greek take two cells
+    pub window_ops: bool,   // let clients move and resize the window with CSI t
+    pub term_fallback: String,   // TERM if the sea-tf terminfo entry is not installed
+    pub trace: Option<TraceTarget>,   // log of the escape sequence handling, see trace.rs, main.rs also takes SEA_TF_TRACE
 }
 impl Config {
     pub fn new() -> Config {
@@ -203,10 +300,76 @@ impl Config {
 	};
 	Config {
 	    dark: false,
+	    foreground: 0x000000,
+	    background: 0xffffff,
+	    cursor_color: None,
+	    palette: ANSI_COLORS,
 	    line_width: 50,
 	    n_lines: 24,
 	    pos: pos,
 	    last_line_border: true,
+	    sync_timeout: Duration::from_millis(150),
+	    cursor_shape: CursorShape::Block,
+	    cursor_blink: false,
+	    blink_interval: Duration::from_millis(500),
+	    image_memory: 16 << 20,
+	    scrollback: 1000,
+	    word_chars: String::from("-_./~:+@%"),
+	    ambiguous_wide: false,
+	    window_ops: false,
+	    term_fallback: String::from("pcansi"),
+	    trace: None,
+	}
+    }
+    // window size in pixels with number_lines lines shown
+    pub(super) fn window_size(&self, (c_w, c_h): (u16, u16), number_lines: usize) -> (u16, u16) {
+	let width = self.line_width.saturating_mul(c_w).saturating_add(6);   // sizes requested with CSI t may be too big
+	let height = (number_lines as u16).saturating_mul(c_h).saturating_add(3);
+	(width, height)
+    }
+}
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+// ESC # 3/4/6, lines without one are single width
+#[derive(PartialEq,Copy,Clone,Debug)]
+pub enum LineSize {
+    DoubleWidth,
+    DoubleTop,
+    DoubleBottom,
+}
+
+// terminal modes set by the client
+pub(super) struct Modes {
+    pub sync: Option<Instant>,   // synchronized output (?2026) started at
+    pub cursor_visible: bool,    // DECTCEM (?25)
+    pub cursor_blink: Option<bool>,   // ?12 or DECSCUSR, None: as in Config
+    pub cursor_shape: Option<CursorShape>,   // DECSCUSR, None: as in Config
+    pub reverse: bool,           // DECSCNM (?5), swaps the colours set by Config::dark
+    pub scheme_reports: bool,    // ?2031, report colour scheme changes
+    pub lr_margins: bool,        // DECLRMM (?69), CSI s sets margins instead of saving the cursor
+    pub bracketed_paste: bool,   // ?2004, pasted text between CSI 200~ and CSI 201~
+    pub autowrap: bool,          // DECAWM (?7), text past the last column continues on the next row
+    pub margins: Option<(usize, usize)>,   // DECSLRM, left and right excluded
+    pub saved_cursor: Option<(usize, usize)>,   // SCOSC, row and column
+}
+impl Modes {
+    pub fn new() -> Modes {
+	Modes {
+	    sync: None,
+	    cursor_visible: true,
+	    cursor_blink: None,
+	    cursor_shape: None,
+	    reverse: false,
+	    scheme_reports: false,
+	    lr_margins: false,
+	    bracketed_paste: false,
+	    autowrap: true,
+	    margins: None,
+	    saved_cursor: None,
 	}
     }
 }
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self,Write,BufWriter};
+use std::path::PathBuf;
+use std::time::Instant;
+
+pub const TRACE_ENV: &str = "SEA_TF_TRACE";
+
+#[derive(PartialEq,Clone,Debug)]
+pub enum TraceTarget {
+    Stderr,
+    File(PathBuf),
+}
+impl TraceTarget {
+    // "-" for stderr, anything else is a file name
+    pub fn parse(arg: &str) -> TraceTarget {
+	match arg {
+	    "-" | "stderr" => TraceTarget::Stderr,
+	    path => TraceTarget::File(PathBuf::from(path)),
+	}
+    }
+    pub fn from_env() -> Option<TraceTarget> {
+	std::env::var(TRACE_ENV).ok().filter(|v| !v.is_empty()).map(|v| TraceTarget::parse(&v))
+    }
+}
+
+// log of everything that goes through State, one line per event prefixed with the seconds since start
+pub struct Trace {
+    out: Box<dyn Write>,
+    start: Instant,
+}
+impl Trace {
+    pub fn open(target: &TraceTarget) -> io::Result<Trace> {
+	let out: Box<dyn Write> = match target {
+	    TraceTarget::Stderr => Box::new(io::stderr()),
+	    TraceTarget::File(path) => Box::new(BufWriter::new(File::create(path)?)),
+	};
+	Ok(Trace::to(out))
+    }
+    pub fn to(out: Box<dyn Write>) -> Trace {
+	Trace {
+	    out: out,
+	    start: Instant::now(),
+	}
+    }
+    // a failing trace must not take the terminal down, errors are dropped
+    pub(super) fn log(&mut self, kind: &str, msg: fmt::Arguments) {
+	let t = self.start.elapsed();
+	let _ = writeln!(self.out, "{:>5}.{:06} {:<7} {}", t.as_secs(), t.subsec_micros(), kind, msg);
+    }
+    pub(super) fn bytes(&mut self, kind: &str, bytes: &[u8]) {
+	self.log(kind, format_args!("\"{}\"", bytes.escape_ascii()));
+    }
+    pub fn flush(&mut self) {
+	let _ = self.out.flush();
+    }
+}
+impl Drop for Trace {
+    fn drop(&mut self) {
+	self.flush();
+    }
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use super::*;
+    use crate::t::State;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+	    self.0.borrow_mut().write(buf)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+	    Ok(())
+	}
+    }
+    #[test]
+    fn trace() {
+	let log = Rc::new(RefCell::new(Vec::new()));
+	let mut state: State<Box<dyn FnMut(Option<usize>)>> = State::new();
+	state.set_trace(Some(Trace::to(Box::new(Shared(log.clone())))));
+	state.write_all(b"a\n\x1b[6n\x1b[?1;2x\x1b]99;x\x07").unwrap();
+	assert_eq!(state.take_reply(), b"\x1b[2;1R");
+	let log = String::from_utf8(log.borrow().clone()).unwrap();
+	let lines: Vec<&str> = log.lines().map(|l| l.trim_start().split_once(' ').unwrap().1).collect();
+	assert_eq!(lines, [
+	    "bytes   \"a\\n\\x1b[6n\\x1b[?1;2x\\x1b]99;x\\x07\"",
+	    "text    \"a\"",
+	    "ascii   LF",
+	    "cmd     DSR Some(6) None",
+	    "unknown CSI ?1;2x",
+	    "unknown OSC 99;x",
+	    "reply   \"\\x1b[2;1R\"",
+	]);
+    }
+}
@@ -1,20 +1,16 @@
 use xcb::{x,Connection};
 use xkbcommon::xkb;
-use super::state::{Config,WindowPosition};
+use std::time::Instant;
+use std::cmp;
+use std::collections::HashMap;
+use std::borrow::Cow;
+use super::state::{Config,CursorShape,LineSize};
 use super::State;
+use super::color::Rgb;
+use super::sixel::Placement;
+use super::select::Clip;
+use super::write::{IMAGE_CELL,WIDE_CELL};
 
-impl WindowPosition {
-    fn get_xy(&self, width: u16, height: u16, screen: &x::Screen) -> (i32,i32) {
-	(
-	    if self.right {
-		screen.width_in_pixels()-self.x_offset-width
-	    } else { self.x_offset } as i32,
-	    if self.bottom {
-		screen.height_in_pixels()-self.y_offset-height
-	    } else { self.y_offset } as i32
-	)
-    }
-}
 pub struct Window {
     pub conn: Connection,
     pub window: x::Window,
@@ -23,7 +19,35 @@ pub struct Window {
     font: x::Charinfo,
     pub xkb_state: xkb::State,
     xkb_state_nomod: xkb::State,
+    focused: bool,
+    blink_on: bool,
+    blink_at: Instant,
+    pixels: HashMap<Rgb, u32>,   // allocated colors
+    fg: u32,
+    cursor: u32,
+    msb_first: bool,   // byte order of images
+    bpp32: bool,       // images of the root depth have 32 bits per pixel, needed to scale lines
+    bg: u32,
+    atoms: Atoms,
+    owned: HashMap<x::Atom, Vec<u8>>,   // text of the selections we own
+    clicks: (x::Timestamp, (usize, usize), u8),   // last press of button 1, its cell and clicks there in a row
+    paste: Option<Paste>,   // selection asked for
+}
+struct Paste {
+    clip: Clip,
+    target: x::Atom,
+    time: x::Timestamp,
+    incr: Option<Vec<u8>>,   // chunks received so far of an INCR transfer
+}
+struct Atoms {
+    clipboard: x::Atom,
+    utf8_string: x::Atom,
+    targets: x::Atom,
+    paste: x::Atom,   // property on our window that pasted text is converted to
+    incr: x::Atom,
 }
+const WHEEL_LINES: isize = 3;
+const CLICK_TIME: x::Timestamp = 400;   // milliseconds between clicks of a double or triple click
 impl Window {
     pub fn get_keysym(&self, ev: &x::KeyPressEvent) -> xkb::Keysym {
 	self.xkb_state_nomod.key_get_one_sym(ev.detail().into())
@@ -31,45 +55,267 @@ impl Window {
     pub fn get_keysym_mod(&mut self, ev: &x::KeyPressEvent) -> xkb::Keysym {
 	self.xkb_state.key_get_one_sym(ev.detail().into())
     }
+    // lines to scroll the view back for Shift+PgUp/PgDn, these keys do not go to the client
+    pub fn scroll_key<T>(&self, key_sym: xkb::Keysym, mask: x::KeyButMask, state: &State<T>) -> Option<isize>
+    where
+	T: FnMut(Option<usize>)
+    {
+	let mods = x::KeyButMask::SHIFT | x::KeyButMask::CONTROL | x::KeyButMask::MOD1;
+	if mask & mods != x::KeyButMask::SHIFT {return None};
+	let page = cmp::max(state.lines.vis_len(), 2) as isize - 1;   // one line stays in view
+	match &xkb::keysym_get_name(key_sym)[..] {
+	    "Prior" | "KP_Prior" => Some(page),
+	    "Next" | "KP_Next" => Some(-page),
+	    _ => None,
+	}
+    }
+    // the same for the mouse wheel, buttons 4 and 5
+    pub fn scroll_button(&self, button: x::Button) -> Option<isize> {
+	match button {
+	    4 => Some(WHEEL_LINES),
+	    5 => Some(-WHEEL_LINES),
+	    _ => None,
+	}
+    }
+    // other clients get text from the selection through selection_request
+    pub fn set_selection(&mut self, clip: Clip, text: Vec<u8>) {
+	let selection = self.clip_atom(clip);
+	self.conn.send_request(&x::SetSelectionOwner {owner: self.window, selection, time: x::CURRENT_TIME});
+	self.owned.insert(selection, text);
+    }
+    pub fn selection_request(&mut self, ev: &x::SelectionRequestEvent) {
+	let (target, requestor) = (ev.target(), ev.requestor());
+	let property = if ev.property() == x::ATOM_NONE {target} else {ev.property()};   // obsolete clients
+	let converted = match self.owned.get(&ev.selection()) {
+	    Some(_) if target == self.atoms.targets => {
+		self.conn.send_request(&x::ChangeProperty {
+		    mode: x::PropMode::Replace,
+		    window: requestor,
+		    property,
+		    r#type: x::ATOM_ATOM,
+		    data: &[self.atoms.targets, self.atoms.utf8_string, x::ATOM_STRING],
+		});
+		true
+	    },
+	    Some(text) if target == self.atoms.utf8_string || target == x::ATOM_STRING => {
+		self.conn.send_request(&x::ChangeProperty {
+		    mode: x::PropMode::Replace,
+		    window: requestor,
+		    property,
+		    r#type: target,
+		    data: &text[..],
+		});
+		true
+	    },
+	    _ => false,
+	};
+	self.conn.send_request(&x::SendEvent {
+	    propagate: false,
+	    destination: x::SendEventDest::Window(requestor),
+	    event_mask: x::EventMask::empty(),
+	    event: &x::SelectionNotifyEvent::new(ev.time(), requestor, ev.selection(), target,
+						 if converted {property} else {x::ATOM_NONE}),
+	});
+    }
+    // another client owns the selection now, true for PRIMARY whose highlight goes
+    pub fn selection_clear(&mut self, ev: &x::SelectionClearEvent) -> bool {
+	self.owned.remove(&ev.selection());
+	ev.selection() == x::ATOM_PRIMARY
+    }
+    // asks the owner of the selection for its text, which comes with SelectionNotify
+    pub fn request_paste(&mut self, clip: Clip, time: x::Timestamp) {
+	let paste = Paste {clip, target: self.atoms.utf8_string, time, incr: None};
+	self.convert(&paste);
+	self.paste = Some(paste);
+    }
+    fn convert(&self, paste: &Paste) {
+	self.conn.send_request(&x::ConvertSelection {
+	    requestor: self.window,
+	    selection: self.clip_atom(paste.clip),
+	    target: paste.target,
+	    property: self.atoms.paste,
+	    time: paste.time,
+	});
+    }
+    // the text asked for, None while it is still coming or if nobody could convert it
+    pub fn paste_notify(&mut self, ev: &x::SelectionNotifyEvent) -> Option<Vec<u8>> {
+	let mut paste = self.paste.take()?;
+	if ev.property() == x::ATOM_NONE {   // STRING if there is no UTF8_STRING, then PRIMARY for an empty CLIPBOARD
+	    if paste.target == self.atoms.utf8_string {
+		paste.target = x::ATOM_STRING;
+	    } else if paste.clip == Clip::Clipboard {
+		(paste.clip, paste.target) = (Clip::Primary, self.atoms.utf8_string);
+	    } else {
+		return None;
+	    }
+	    self.convert(&paste);
+	    self.paste = Some(paste);
+	    return None;
+	}
+	let (kind, data) = self.take_property()?;
+	if kind == self.atoms.incr {   // in chunks, each one announced by PropertyNotify
+	    paste.incr = Some(Vec::new());
+	    self.paste = Some(paste);
+	    return None;
+	}
+	Some(self.decode_paste(kind, data))
+    }
+    // next chunk of an INCR transfer, an empty one ends it
+    pub fn paste_property(&mut self, ev: &x::PropertyNotifyEvent) -> Option<Vec<u8>> {
+	if ev.window() != self.window || ev.atom() != self.atoms.paste || ev.state() != x::Property::NewValue {
+	    return None;
+	}
+	self.paste.as_ref()?.incr.as_ref()?;
+	let (kind, data) = self.take_property()?;
+	let paste = self.paste.as_mut()?;
+	let text = paste.incr.as_mut()?;
+	if !data.is_empty() {
+	    text.extend_from_slice(&data);
+	    return None;
+	}
+	let text = std::mem::take(text);
+	self.paste = None;
+	Some(self.decode_paste(kind, text))
+    }
+    // type and value of the paste property, deleted so that the owner can go on
+    fn take_property(&self) -> Option<(x::Atom, Vec<u8>)> {
+	let cookie = self.conn.send_request(&x::GetProperty {
+	    delete: true,
+	    window: self.window,
+	    property: self.atoms.paste,
+	    r#type: x::ATOM_ANY,
+	    long_offset: 0,
+	    long_length: u32::MAX/4,
+	});
+	let reply = self.conn.wait_for_reply(cookie).ok()?;
+	Some((reply.r#type(), reply.value::<u8>().to_vec()))
+    }
+    // STRING is Latin-1
+    fn decode_paste(&self, kind: x::Atom, data: Vec<u8>) -> Vec<u8> {
+	if kind != x::ATOM_STRING {return data};
+	data.iter().map(|b| char::from(*b)).collect::<String>().into_bytes()
+    }
+    fn clip_atom(&self, clip: Clip) -> x::Atom {
+	match clip {
+	    Clip::Primary => x::ATOM_PRIMARY,
+	    Clip::Clipboard => self.atoms.clipboard,
+	}
+    }
+    // row and column of the window at a pointer position
+    pub fn cell_at(&self, x: i16, y: i16) -> (usize, usize) {
+	let (c_w, l_h) = self.cell_size();
+	(cmp::max(y-3, 0) as usize/l_h as usize, cmp::max(x-3, 0) as usize/c_w as usize)
+    }
+    // cell of a button 1 press and the clicks there in a row, up to three
+    pub fn click(&mut self, ev: &x::ButtonPressEvent) -> ((usize, usize), u8) {
+	let cell = self.cell_at(ev.event_x(), ev.event_y());
+	let (time, at, n) = self.clicks;
+	let n = if at == cell && ev.time().wrapping_sub(time) < CLICK_TIME {n%3 + 1} else {1};
+	self.clicks = (ev.time(), cell, n);
+	(cell, n)
+    }
+    // width of a character and distance between lines in pixels
+    pub fn cell_size(&self) -> (u16, u16) {
+	(self.font.character_width as u16, (3+self.font.ascent+self.font.descent) as u16)
+    }
+    pub fn screen_size(&self) -> (u16, u16) {
+	(self.screen.width_in_pixels(), self.screen.height_in_pixels())
+    }
     pub fn reload_keymap(&mut self) {
 	self.xkb_state = Self::reload_xkb_map(&self.conn);
-	self.xkb_state_nomod = self.xkb_state.clone();
+	self.xkb_state_nomod = xkb::State::new(&self.xkb_state.get_keymap());
+    }
+    pub fn set_focus<T>(&mut self, focused: bool, state: &mut State<T>)
+    where
+	T: FnMut(Option<usize>)
+    {
+	self.focused = focused;
+	self.reset_blink(&state.config);
+	state.do_and_redraw(|st| st.redraw_cursor(), self);
+    }
+    // keep the cursor visible while typing
+    pub fn reset_blink(&mut self, config: &Config) {
+	self.blink_on = true;
+	self.blink_at = Instant::now() + config.blink_interval;
+    }
+    // next time the cursor has to be redrawn for blinking
+    pub fn blink_deadline<T>(&self, state: &State<T>) -> Option<Instant>
+    where
+	T: FnMut(Option<usize>)
+    {
+	if self.focused && state.cursor_blinks() {Some(self.blink_at)} else {None}
+    }
+    pub fn blink<T>(&mut self, state: &mut State<T>)
+    where
+	T: FnMut(Option<usize>)
+    {
+	if self.blink_deadline(state).map_or(true, |d| Instant::now() < d) {return};
+	self.blink_on = !self.blink_on;
+	self.blink_at = Instant::now() + state.config.blink_interval;
+	state.do_and_redraw(|st| st.redraw_cursor(), self);
     }
     pub fn redraw<T>(&mut self, state: &State<T>)
     where
 	T: FnMut(Option<usize>)
     {
 	let l_h = 3+self.font.ascent+self.font.descent;
+	let shape = if state.modes.cursor_visible && (self.blink_on || !self.focused || !state.cursor_blinks()) {
+	    Some(state.cursor_shape())
+	} else {None};
+	let (fg, bg) = state.fg_bg();
+	let (fg, bg) = (self.pixel(fg), self.pixel(bg));
+	self.fg = fg;
+	self.bg = bg;
+	self.cursor = self.pixel(state.cursor_color());
 	//println!("redrawing lines: {:?}",state.redraw_lines);
-	if let Some(numbers) = &state.redraw_lines {
-	    let (white,black) = (self.screen.white_pixel(),self.screen.black_pixel());
-	    let (fg,bg) = if state.config.dark {(white,black)}else{(black,white)};
-	    
-	    self.conn.send_request(&x::ChangeGc { gc: self.gc, value_list: &[
-		x::Gc::Foreground(bg)],});
-	    for n in numbers {
-		self.conn.send_request(&x::PolyFillRectangle {
-		    drawable: x::Drawable::Window(self.window),
-		    gc: self.gc,
-		    rectangles: &[x::Rectangle {
-			x: 3, y: 3 + l_h*(*n+state.lines.start) as i16,                                        // 
-			width: state.config.line_width*self.font.character_width as u16,
-			height: (self.font.ascent+self.font.descent) as u16,
-		    }],
-		});
-	    }
-	    self.conn.send_request(&x::ChangeGc { gc: self.gc, value_list: &[
-		x::Gc::Foreground(fg)],});
-	    for n in numbers {
-		let cur = if *n == state.lines.cur() {Some(state.col as u16)} else {None};
-		self.draw_text_line(&state.lines[*n+state.lines.start], *n, cur, state.config.line_width);  // 
-	    }
-	} else {
-	    self.apply(&state.config, state.lines.vis_len());
-	    for n in 0..state.lines.vis_len() {
-		let cur = if n == state.lines.cur() {Some(state.col as u16)} else {None};
-		self.draw_text_line(&state.lines[n+state.lines.start], n, cur, state.config.line_width);  // 
-	    }
+	let offset = state.view_offset();
+	match &state.redraw_lines {
+	    Some(numbers) if offset == 0 && !state.searching() && !state.copying() => {
+		self.conn.send_request(&x::ChangeGc { gc: self.gc, value_list: &[
+		    x::Gc::Foreground(bg)],});
+		for n in numbers {
+		    self.conn.send_request(&x::PolyFillRectangle {
+			drawable: x::Drawable::Window(self.window),
+			gc: self.gc,
+			rectangles: &[x::Rectangle {
+			    x: 3, y: 3 + l_h*(*n+state.lines.start) as i16,                                        // 
+			    width: state.config.line_width*self.font.character_width as u16,
+			    height: (self.font.ascent+self.font.descent) as u16,
+			}],
+		    });
+		}
+		self.conn.send_request(&x::ChangeGc { gc: self.gc, value_list: &[
+		    x::Gc::Foreground(fg)],});
+		for n in numbers {
+		    let cur = if *n == state.lines.cur() {Some(state.col as u16)} else {None};
+		    self.draw_text_line(&state.lines[*n+state.lines.start], *n, cur, state.config.line_width, shape,
+					state.line_size(*n+state.lines.start), &state.marks(*n));  // 
+		}
+		// text drawn over an image erased it
+		for p in &state.images {
+		    if numbers.iter().any(|n| (p.row..p.row+p.rows).contains(n)) {
+			self.draw_image(p, state.lines.start as isize, bg);
+		    }
+		}
+	    },
+	    _ => {   // scrolled back, searching or copying, the top rows come from the scrollback
+		self.apply(&state.config, fg, bg, state.lines.vis_len());
+		for n in 0..state.lines.vis_len() {
+		    let (line, row) = state.view_line(n);
+		    let cur = state.view_cursor(n).map(|col| col as u16);
+		    self.draw_text_line(line, n, cur, state.config.line_width, shape,
+					row.and_then(|row| state.line_size(row)), &state.marks(n));
+		}
+		let start = state.lines.start as isize - offset as isize;
+		for p in &state.images {
+		    if p.row+p.rows+offset > state.lines.start && p.row+offset < state.lines.end {
+			self.draw_image(p, start, bg);
+		    }
+		}
+		if let Some(prompt) = state.search_prompt() {
+		    self.draw_overlay(prompt.as_bytes(), state.lines.vis_len()-1, state.config.line_width);
+		}
+	    },
 	}
 	
     }
@@ -94,6 +340,9 @@ impl Window {
 	
 	let setup = conn.get_setup();
 	let screen = setup.roots().nth(screen_num as usize).unwrap().to_owned();
+	let msb_first = setup.image_byte_order() == x::ImageOrder::MsbFirst;
+	let bpp32 = setup.pixmap_formats().iter()
+	    .any(|f| f.depth() == screen.root_depth() && f.bits_per_pixel() == 32);
 
 	let window: x::Window = conn.generate_id();
 	let w_cookie = conn.send_request_checked(&x::CreateWindow {
@@ -111,6 +360,10 @@ impl Window {
 		x::Cw::OverrideRedirect(true),
 		x::Cw::EventMask(x::EventMask::KEY_PRESS |
 				 x::EventMask::KEY_RELEASE |
+				 x::EventMask::BUTTON_PRESS |
+				 x::EventMask::BUTTON_RELEASE |
+				 x::EventMask::BUTTON1_MOTION |
+				 x::EventMask::PROPERTY_CHANGE |
 				 x::EventMask::FOCUS_CHANGE),],
 	});
 	conn.check_request(w_cookie)?;
@@ -126,9 +379,21 @@ impl Window {
 	conn.check_request(gc_cookie)?;
 	conn.send_request_checked(&x::CloseFont {font} );
 
+	let atom = |name: &[u8]| -> Result<x::Atom, xcb::Error> {
+	    let cookie = conn.send_request(&x::InternAtom {only_if_exists: false, name});
+	    Ok(conn.wait_for_reply(cookie)?.atom())
+	};
+	let atoms = Atoms {
+	    clipboard: atom(b"CLIPBOARD")?,
+	    utf8_string: atom(b"UTF8_STRING")?,
+	    targets: atom(b"TARGETS")?,
+	    paste: atom(b"SEA_TF_PASTE")?,
+	    incr: atom(b"INCR")?,
+	};
+
 	Self::setup_xkb(&conn);
 	let xkb_state = Self::reload_xkb_map(&conn);
-	let xkb_state_nomod = xkb_state.clone();
+	let xkb_state_nomod = xkb::State::new(&xkb_state.get_keymap());  // clone would share modifiers
 	
 	let mut my_window_instance = Window {
 	    conn: conn,
@@ -137,7 +402,20 @@ impl Window {
 	    screen: Box::new(screen),
 	    font: font_max_info,
 	    xkb_state: xkb_state,
-	    xkb_state_nomod: xkb_state_nomod
+	    xkb_state_nomod: xkb_state_nomod,
+	    focused: false,
+	    blink_on: true,
+	    blink_at: Instant::now() + state.config.blink_interval,
+	    pixels: HashMap::new(),
+	    fg: 0,
+	    cursor: 0,
+	    msb_first: msb_first,
+	    bpp32: bpp32,
+	    bg: 0,
+	    atoms,
+	    owned: HashMap::new(),
+	    clicks: (0, (0, 0), 0),
+	    paste: None,
 	};
 
 	my_window_instance.conn.send_request(&x::MapWindow {window});
@@ -150,12 +428,59 @@ impl Window {
 	my_window_instance.conn.flush()?;
 	Ok(my_window_instance)
     }
-    fn apply(&mut self, config: &Config, number_lines: usize) {
-	let (white,black) = (self.screen.white_pixel(),self.screen.black_pixel());
-	let (fg,bg) = if config.dark {(white,black)}else{(black,white)};
-	let width: u16 = 6+config.line_width*self.font.character_width as u16;
-	let height: u16 = 3+number_lines as u16*(3+self.font.ascent+self.font.descent) as u16;
-	let (x,y) = config.pos.get_xy(width, height, &self.screen);
+    // pixel value for rgb, black or white if the color cannot be allocated
+    fn pixel(&mut self, rgb: Rgb) -> u32 {
+	if let Some(pixel) = self.pixels.get(&rgb) {return *pixel};
+	let (r, g, b) = ((rgb >> 16 & 0xff) as u16, (rgb >> 8 & 0xff) as u16, (rgb & 0xff) as u16);
+	let cookie = self.conn.send_request(&x::AllocColor {
+	    cmap: self.screen.default_colormap(),
+	    red: r*257, green: g*257, blue: b*257,
+	});
+	let pixel = match self.conn.wait_for_reply(cookie) {
+	    Ok(reply) => reply.pixel(),
+	    Err(_) if r+g+b > 3*0x7f => self.screen.white_pixel(),
+	    Err(_) => self.screen.black_pixel(),
+	};
+	self.pixels.insert(rgb, pixel);
+	pixel
+    }
+    // transparent pixels get bg, sent in bands to stay below the maximum request size
+    fn draw_image(&mut self, p: &Placement, start: isize, bg: u32) {
+	let (c_w, l_h) = self.cell_size();
+	let image = &p.image;
+	let x = 3 + c_w as i32*p.col as i32;
+	let y = 3 + l_h as i32*(p.row as i32-start as i32);
+	let skip = cmp::max(0, 3-y) as usize;   // rows scrolled off the top
+	if image.width == 0 || skip >= image.height || x > i16::MAX as i32 {return};
+	let band = cmp::max(1, 16384/image.width);
+	let mut data = Vec::with_capacity(image.width*band*4);
+	for (i, rows) in image.rgba.chunks(image.width*4).skip(skip).collect::<Vec<_>>().chunks(band).enumerate() {
+	    data.clear();
+	    for px in rows.iter().flat_map(|row| row.chunks(4)) {
+		let pixel = if px[3] == 0 {bg} else {
+		    self.pixel((px[0] as Rgb) << 16 | (px[1] as Rgb) << 8 | px[2] as Rgb)
+		};
+		data.extend_from_slice(&if self.msb_first {pixel.to_be_bytes()} else {pixel.to_le_bytes()});
+	    }
+	    let dst_y = y + (skip + i*band) as i32;
+	    if dst_y > i16::MAX as i32 {break};
+	    self.conn.send_request(&x::PutImage {
+		format: x::ImageFormat::ZPixmap,
+		drawable: x::Drawable::Window(self.window),
+		gc: self.gc,
+		width: image.width as u16,
+		height: rows.len() as u16,
+		dst_x: x as i16,
+		dst_y: dst_y as i16,
+		left_pad: 0,
+		depth: self.screen.root_depth(),
+		data: &data,
+	    });
+	}
+    }
+    fn apply(&mut self, config: &Config, fg: u32, bg: u32, number_lines: usize) {
+	let (width, height) = config.window_size(self.cell_size(), number_lines);
+	let (x,y) = config.pos.get_xy(width, height, self.screen_size());
 	
 	self.conn.send_request(&x::ConfigureWindow {
 	    window: self.window,
@@ -192,7 +517,94 @@ impl Window {
 	}
 	
     }
-    fn draw_text_line(&mut self, line: &[u8], row: usize, cur: Option<u16>, line_width: u16) {
+    // double width and height lines are drawn at half the width into a pixmap and scaled up
+    // marks: highlighted start and end columns, true for the one standing out
+    fn draw_text_line(&mut self, line: &[u8], row: usize, cur: Option<u16>, line_width: u16,
+		      shape: Option<CursorShape>, size: Option<LineSize>, marks: &[(usize, usize, bool)]) {
+	let (c_w, l_h) = self.cell_size();
+	let top = 3 + l_h as i16*row as i16;
+	let Some(size) = size.filter(|_| self.bpp32) else {
+	    return self.draw_text(x::Drawable::Window(self.window), line, top, cur, line_width, shape, marks);
+	};
+	let half = cmp::max(line_width/2, 1);
+	let width = half.saturating_mul(c_w);
+	let pixmap: x::Pixmap = self.conn.generate_id();
+	self.conn.send_request(&x::CreatePixmap {
+	    depth: self.screen.root_depth(),
+	    pid: pixmap,
+	    drawable: x::Drawable::Window(self.window),
+	    width: width.saturating_add(3),
+	    height: l_h,
+	});
+	self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[x::Gc::Foreground(self.bg)]});
+	self.conn.send_request(&x::PolyFillRectangle {
+	    drawable: x::Drawable::Pixmap(pixmap),
+	    gc: self.gc,
+	    rectangles: &[x::Rectangle {x: 0, y: 0, width: width.saturating_add(3), height: l_h}],
+	});
+	self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[x::Gc::Foreground(self.fg)]});
+	self.draw_text(x::Drawable::Pixmap(pixmap), line, 0, cur, half, shape, marks);
+	let cookie = self.conn.send_request(&x::GetImage {
+	    format: x::ImageFormat::ZPixmap,
+	    drawable: x::Drawable::Pixmap(pixmap),
+	    x: 3, y: 0, width, height: l_h,
+	    plane_mask: u32::MAX,
+	});
+	let reply = self.conn.wait_for_reply(cookie);
+	self.conn.send_request(&x::FreePixmap {pixmap});
+	let Ok(reply) = reply else {return};
+	let (src, stride, l_h) = (reply.data(), width as usize*4, l_h as usize);
+	if src.len() < stride*l_h {return};
+	let mut data = Vec::with_capacity(2*stride*l_h);
+	for y in 0..l_h {
+	    let y = match size {
+		LineSize::DoubleWidth => y,
+		LineSize::DoubleTop => y/2,
+		LineSize::DoubleBottom => (l_h+y)/2,
+	    };
+	    for px in src[y*stride..(y+1)*stride].chunks(4) {
+		data.extend_from_slice(px);
+		data.extend_from_slice(px);
+	    }
+	}
+	self.conn.send_request(&x::PutImage {
+	    format: x::ImageFormat::ZPixmap,
+	    drawable: x::Drawable::Window(self.window),
+	    gc: self.gc,
+	    width: width.saturating_mul(2),
+	    height: l_h as u16,
+	    dst_x: 3,
+	    dst_y: top,
+	    left_pad: 0,
+	    depth: self.screen.root_depth(),
+	    data: &data,
+	});
+    }
+    // status text over row, in swapped colours across the whole line
+    fn draw_overlay(&mut self, text: &[u8], row: usize, line_width: u16) {
+	let (c_w, l_h) = self.cell_size();
+	let top = 3 + l_h as i16*row as i16;
+	let text = &text[text.len().saturating_sub(line_width as usize)..];   // the end of a long pattern
+	self.conn.send_request(&x::PolyFillRectangle {
+	    drawable: x::Drawable::Window(self.window),
+	    gc: self.gc,
+	    rectangles: &[x::Rectangle {x: 3, y: top, width: line_width.saturating_mul(c_w), height: l_h-3}],
+	});
+	self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[
+	    x::Gc::Foreground(self.bg), x::Gc::Background(self.fg)]});
+	self.conn.send_request(&x::ImageText8 {
+	    drawable: x::Drawable::Window(self.window),
+	    gc: self.gc,
+	    x: 3,
+	    y: top + self.font.ascent,
+	    string: text,
+	});
+	self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[
+	    x::Gc::Foreground(self.fg), x::Gc::Background(self.bg)]});
+    }
+    // top: y of the line in drawable
+    fn draw_text(&mut self, drawable: x::Drawable, line: &[u8], top: i16, cur: Option<u16>, line_width: u16,
+		 shape: Option<CursorShape>, marks: &[(usize, usize, bool)]) {
 	let lpad = 5;
 	let line_len = line.len() as u16;
 	let (start, end, offs) = {
@@ -204,24 +616,55 @@ impl Window {
 	    } else {(0,line_width-1,0)}
 	};
 	
+	let mut text = Cow::Borrowed(&line[start as usize..end as usize]);
+	if text.iter().any(|c| *c == IMAGE_CELL || *c == WIDE_CELL) {
+	    for c in text.to_mut().iter_mut().filter(|c| **c == IMAGE_CELL || **c == WIDE_CELL) {*c = b' '};
+	}
 	self.conn.send_request(&x::ImageText8 {
-	    drawable: x::Drawable::Window(self.window),
+	    drawable,
 	    gc: self.gc,
 	    x: 3 + offs,
-	    y: 3 + self.font.ascent + (3+self.font.ascent+self.font.descent)*row as i16,
-	    string: &line[start as usize..end as usize],
+	    y: top + self.font.ascent,
+	    string: &text,
 	});
-	if let Some(cur) = cur {
-	    self.conn.send_request(&x::PolyFillRectangle {
-		drawable: x::Drawable::Window(self.window),
+	for &(s, e, current) in marks {   // drawn again with the colours swapped
+	    let (s, e) = (cmp::max(s, start as usize), cmp::min(e, end as usize));
+	    if s >= e {continue};
+	    let bg = if current {self.cursor} else {self.fg};
+	    self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[
+		x::Gc::Foreground(self.bg), x::Gc::Background(bg)]});
+	    self.conn.send_request(&x::ImageText8 {
+		drawable,
 		gc: self.gc,
-		rectangles: &[x::Rectangle {
-		    x: 3+self.font.character_width*(cur as i16-start as i16)+offs,
-		    y: 3 + (3+self.font.ascent+self.font.descent)*row as i16,
-		    width: self.font.character_width as u16,
-		    height: (self.font.ascent+self.font.descent) as u16,
-		}],
+		x: 3 + offs + self.font.character_width*(s-start as usize) as i16,
+		y: top + self.font.ascent,
+		string: &text[s-start as usize..e-start as usize],
 	    });
+	    self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[
+		x::Gc::Foreground(self.fg), x::Gc::Background(self.bg)]});
+	}
+	if let (Some(cur), Some(shape)) = (cur, shape) {
+	    let (c_w, c_h) = (self.font.character_width, self.font.ascent+self.font.descent);
+	    let (x, y) = (3+c_w*(cur as i16-start as i16)+offs, top);
+	    self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[x::Gc::Foreground(self.cursor)]});
+	    if !self.focused {
+		self.conn.send_request(&x::PolyRectangle {
+		    drawable,
+		    gc: self.gc,
+		    rectangles: &[x::Rectangle {x, y, width: c_w as u16-1, height: c_h as u16-1}],
+		});
+	    } else {
+		self.conn.send_request(&x::PolyFillRectangle {
+		    drawable,
+		    gc: self.gc,
+		    rectangles: &[match shape {
+			CursorShape::Block => x::Rectangle {x, y, width: c_w as u16, height: c_h as u16},
+			CursorShape::Underline => x::Rectangle {x, y: y+c_h-2, width: c_w as u16, height: 2},
+			CursorShape::Bar => x::Rectangle {x, y, width: 2, height: c_h as u16},
+		    }],
+		});
+	    }
+	    self.conn.send_request(&x::ChangeGc {gc: self.gc, value_list: &[x::Gc::Foreground(self.fg)]});
 	}
     }
     fn setup_xkb(conn: &xcb::Connection) {
@@ -265,6 +708,16 @@ impl Window {
 	    }],
 	});
 	conn.check_request(cookie).expect("failed to select notify events from xcb xkb");
+	// only send KeyRelease when the key is actually released, repeats are KeyPress only
+	let cookie = conn.send_request(&xcb::xkb::PerClientFlags {
+	    device_spec: xcb::xkb::Id::UseCoreKbd as xcb::xkb::DeviceSpec,
+	    change: xcb::xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+	    value: xcb::xkb::PerClientFlag::DETECTABLE_AUTO_REPEAT,
+	    ctrls_to_change: xcb::xkb::BoolCtrl::empty(),
+	    auto_ctrls: xcb::xkb::BoolCtrl::empty(),
+	    auto_ctrls_values: xcb::xkb::BoolCtrl::empty(),
+	});
+	conn.wait_for_reply(cookie).expect("failed to enable detectable auto repeat");
     }
     fn reload_xkb_map(conn: &xcb::Connection) -> xkb::State {
 	let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
@@ -1,190 +1,162 @@
 use std::cmp;
-use super::state::QueueView;
+use std::fmt;
+use std::time::Instant;
+use super::state::{QueueView,CursorShape,WindowPosition,LineSize};
 use super::State;
-
-#[derive(Clone)]
-pub(super) struct EscSeq {
-    pub esc: Esc,
-    pub seq: Vec<u8>,
-    pub inv: bool,
-}
-#[derive(PartialEq,Copy,Clone,Debug)]
-pub(super) enum Esc {
-    None,
-    Esc,
-    CSI,
-    NF,
-    STT,
-}
-pub(super) trait PreWrite {
-    fn pre_write(&mut self, arg: &[u8], pre: &mut EscSeq) -> usize;
-}
-
-trait WriteANSI {
-    fn write_ansi(&mut self, esc_seq: &EscSeq);
-}
-impl<T: WriteANSI + WriteASCII> PreWrite for T {
-    fn pre_write(&mut self, arg: &[u8], pre: &mut EscSeq) -> usize{
-	let mut i: usize = 0;
-	if arg.is_empty() {return 0};
-	if pre.esc == Esc::Esc {
-	    pre.esc = match arg[i] {
-		b'[' => { i+=1;  Esc::CSI },
-		b'\\' | b'7' | b'8' | b'c' | b'N' | b'O' => { i+=1;  Esc::None },
-		other => if other & 0xf0 == 0x20 { Esc::NF } else { Esc::STT },
-	    }
-	} else {
-	    let l = arg[i..].into_iter().take_while(match pre.esc {
-		Esc::None => Box::new(|b: &&u8| **b >= 0x20 && **b != 0x7f)
-		    as Box<dyn Fn(&&u8)->bool>,
-		Esc::CSI => Box::new(|b: &&u8| **b & 0xe0 == 0x20),
-		Esc::NF => Box::new(|b: &&u8| **b & 0xf0 == 0x20),
-		Esc::STT => Box::new(|b: &&u8| **b != 0x1b),
-		Esc::Esc => panic!("unexpected Esc type change!"),
-	    }).copied().map(if pre.esc==Esc::None && pre.inv {Box::new(|b: u8| match b & 0xc0 {
-		0x80 => (),                   // utf8 tail
-		0xc0 => pre.seq.push(b'?'),   // utf8 head
-		_ if b == b' ' => pre.seq.push(b'#'),
-		_ => pre.seq.push(b),        // ascii char
-	    }) as Box<dyn FnMut(u8)>} else {Box::new(|b: u8| match b & 0xc0 {
-		0x80 => (),                   // utf8 tail
-		0xc0 => pre.seq.push(b'?'),   // utf8 head
-		_ => pre.seq.push(b),        // ascii char
-	    })}).count();
-	    if l == 0 {
-		i += match pre.esc {
-		    Esc::None => {
-			self.write_ascii(match arg[i] {
-			    0x1b => {
-				pre.esc=Esc::Esc;
-				EscASCII::None
-			    },
-			    0x07 => EscASCII::BEL,
-			    0x08 => EscASCII::BS,
-			    0x09 => EscASCII::HT,
-			    0x0a => EscASCII::LF,
-			    0x0d => EscASCII::CR,
-			    0x7f => EscASCII::DEL,
-			    _ => EscASCII::None,
-			});
-			1
-		    },
-		    Esc::CSI | Esc::NF => {
-			pre.seq.push(arg[i]);
-			self.write_ansi(pre);
-			pre.esc = Esc::None;
-			pre.seq = Vec::new();
-			1
-		    },
-		    Esc::STT => {
-			self.write_ansi(pre);
-			pre.esc = Esc::None;
-			pre.seq = Vec::new();
-			0
-		    },
-		    Esc::Esc => panic!("unexpected Esc type change!"),
-		};
-	    } else if pre.esc == Esc::None {
-		self.write_ansi(pre);
-		pre.seq = Vec::new();
-	    }
-	    i+=l;
-	}
-	i
-    }
-}
+use super::pty::KKP_FLAGS;
+use super::color;
+use super::caps;
+use super::width;
+use super::sixel::{Sixel,Image,Placement};
+use super::parse::{Params,Perform};
 
 #[cfg(test)]
-mod pre_write_tests {
+mod parser_tests {
     use super::*;
+    use crate::t::parse::Parser;
     use std::io;
     use std::io::Write;
     #[test]
     fn csi_interrupted() {
-	let mut st = PreWriteTest::new();
+	let mut st = ParserTest::new();
 	st.write_all("\x1b".as_bytes());
 	st.write_all("[A\x1b[".as_bytes());
 	st.write_all("13B\x1b[F".as_bytes());
 	st.write_all("hallo".as_bytes());
-	assert_eq!(st.res, "<No ASCII><CSI:>A<No ASCII><CSI:>13B<No ASCII><CSI:>F<TXT:>hallo");
+	assert_eq!(st.res, "<CSI:>A<CSI:>13B<CSI:>F<TXT:>hallo");
     }
     #[test]
     fn basic_csi() {
-	let mut st = PreWriteTest::new();
-	st.write_all("1\x1b[13;5fb".as_bytes());
-	assert_eq!(st.res, "<TXT:>1<No ASCII><CSI:>13;5f<TXT:>b");
+	let mut st = ParserTest::new();
+	st.write_all("1\x1b[13;5fb\x1b[?1;2$p\x1b[4:3m".as_bytes());
+	assert_eq!(st.res, "<TXT:>1<CSI:>13;5f<TXT:>b<CSI:>?1;2$p<CSI:>4:3m");
+    }
+    #[test]
+    fn single_final() {
+	let mut st = ParserTest::new();
+	st.write_all("a\x1bcb\x1bN\x1b(B".as_bytes());
+	assert_eq!(st.res, "<TXT:>a<ESC:>c<TXT:>b<EXE:8e><ESC:>(B");
     }
     #[test]
     fn basic_text() {
-	let mut st = PreWriteTest::new();
+	let mut st = ParserTest::new();
 	st.write_all("hallo".as_bytes());
 	assert_eq!(st.res, "<TXT:>hallo");
     }
     #[test]
     fn ascii_control_characters() {
-	let mut st = PreWriteTest::new();
+	let mut st = ParserTest::new();
 	st.write_all("\x01\x07\x08\x09\x0a\x0c\x0d\x7f".as_bytes());
-	assert_eq!(st.res, "<No ASCII><BEL><BS><HT><LF><No ASCII><CR><DEL>");
+	assert_eq!(st.res, "<EXE:01><EXE:07><EXE:08><EXE:09><EXE:0a><EXE:0c><EXE:0d>");
+    }
+    #[test]
+    fn control_in_csi() {
+	let mut st = ParserTest::new();
+	st.write_all("\x1b[1\n2A\x1b[12\x18A\x1b[3\x1aB\x1b[1\x1b[2C".as_bytes());
+	assert_eq!(st.res, "<EXE:0a><CSI:>12A<EXE:18><TXT:>A<EXE:1a><TXT:>B<CSI:>2C");
+	assert!(st.flush().is_ok());
+	st.write_all("\x1b[1".as_bytes());
+	assert!(st.flush().is_err());
+    }
+    #[test]
+    fn c1_controls() {
+	let mut st = ParserTest::new();
+	st.write_all(b"\x9b2J\xc2\x9b3J\x1b[4J\x85\xc2\x85");
+	assert_eq!(st.res, "<CSI:>2J<CSI:>3J<CSI:>4J<EXE:85><EXE:85>");
+    }
+    #[test]
+    fn strings() {
+	let mut st = ParserTest::new();
+	st.write_all("\x1b]0;tit".as_bytes());
+	st.write_all("le\x07\x1b]2;a;b\x1b\\\u{9d}1\u{9c}\x1b]2;x\x18".as_bytes());
+	st.write_all("\x1bP1$qm\x1b\\\x1b_apc\x1b\\\x1bXs\u{9c}b".as_bytes());
+	assert_eq!(st.res, "<OSC:>0|title|BEL<OSC:>2|a|b<OSC:>1<EXE:18>\
+			    <DCS:>1$q<PUT:>m<UNHOOK><TXT:>b");
+    }
+    #[test]
+    fn utf8() {
+	let mut st = ParserTest::new();
+	st.write_all(b"a\xc3");
+	assert!(st.flush().is_err());
+	st.write_all(b"\xa4\xff\xe2\x82b\xed\xa0\x80");
+	assert_eq!(st.res, "<TXT:>a<TXT:>ä<TXT:>\u{fffd}<TXT:>\u{fffd}<TXT:>b<TXT:>\u{fffd}<TXT:>\u{fffd}<EXE:80>");
     }
-    struct PreWriteTest {
+    #[test]
+    fn garbage() {
+	let mut st = ParserTest::new();
+	let mut x: u32 = 1;
+	let bytes: Vec<u8> = (0..100000).map(|_| {
+	    x = x.wrapping_mul(1103515245).wrapping_add(12345);
+	    (x >> 16) as u8
+	}).collect();
+	for chunk in bytes.chunks(97) {
+	    st.write_all(chunk).unwrap();
+	}
+	assert!(!st.res.is_empty());
+    }
+    struct ParserTest {
 	res: String,
-	leftover: EscSeq,
+	parser: Parser,
     }
-    impl PreWriteTest {
-	fn new() -> PreWriteTest {
-	    PreWriteTest {
+    impl ParserTest {
+	fn new() -> ParserTest {
+	    ParserTest {
 		res: String::new(),
-		leftover: EscSeq {
-		    esc: Esc::None,
-		    seq: Vec::new(),
-		    inv: false,
-		},
+		parser: Parser::new(),
 	    }
 	}
     }
-    impl WriteASCII for PreWriteTest {
-	fn write_ascii(&mut self, esc: EscASCII) {
-	    self.res += match esc {
-		EscASCII::None => "<No ASCII>",
-		EscASCII::BEL => "<BEL>",
-		EscASCII::BS => "<BS>",
-		EscASCII::HT => "<HT>",
-		EscASCII::LF => "<LF>",
-		EscASCII::CR => "<CR>",
-		EscASCII::DEL => "<DEL>",
-	    };
+    impl Perform for ParserTest {
+	fn print(&mut self, text: &[u8]) {
+	    self.res += "<TXT:>";
+	    self.res += std::str::from_utf8(text).expect("<invalid utf8>");
 	}
-    }
-    impl WriteANSI for PreWriteTest {
-	fn write_ansi(&mut self, esc_seq: &EscSeq) {
-	    let (esc,seq) = (esc_seq.esc, &esc_seq.seq[..]);
-	    self.res += match esc {
-		Esc::None => "<TXT:>",
-		Esc::Esc => panic!("write_ansi called after only \\e"),
-		Esc::CSI => "<CSI:>",
-		Esc::NF => "<NF:>",
-		Esc::STT => "<STT:>",
+	fn execute(&mut self, byte: u8) {
+	    self.res += &format!("<EXE:{:02x}>", byte);
+	}
+	fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: u8) {
+	    let (private, intermediates) = match intermediates.first() {
+		Some(b'<'..=b'?') => (&intermediates[..1], &intermediates[1..]),
+		_ => (&intermediates[..0], intermediates),
 	    };
-	    self.res += std::str::from_utf8(seq).expect("<invalid utf8>");
+	    self.res += &format!("<CSI:>{}{}{}{}", String::from_utf8_lossy(private), params,
+				 String::from_utf8_lossy(intermediates), action as char);
+	}
+	fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+	    self.res += &format!("<ESC:>{}{}", String::from_utf8_lossy(intermediates), byte as char);
+	}
+	fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: u8) {
+	    self.res += &format!("<DCS:>{}{}{}", params, String::from_utf8_lossy(intermediates), action as char);
+	}
+	fn put(&mut self, data: &[u8]) {
+	    self.res += &format!("<PUT:>{}", String::from_utf8_lossy(data));
+	}
+	fn unhook(&mut self) {
+	    self.res += "<UNHOOK>";
+	}
+	fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+	    self.res += "<OSC:>";
+	    let params: Vec<_> = params.iter().map(|p| String::from_utf8_lossy(p)).collect();
+	    self.res += &params.join("|");
+	    if bell_terminated {self.res += "|BEL"};
 	}
     }
-    impl io::Write for PreWriteTest {
+    impl io::Write for ParserTest {
 	fn flush(&mut self) -> io::Result<()> {
-	    if self.leftover.esc == Esc::None && self.leftover.seq.is_empty() {
+	    if self.parser.is_ground() {
 		Ok(())
 	    } else {
 		Err(io::Error::new(io::ErrorKind::Other,"Incomplete control sequence cannot be flushed!"))
 	    }
 	}
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-	    let mut esc_seq = self.leftover.clone();
-	    let l = self.pre_write(buf, &mut esc_seq);
-	    self.leftover = esc_seq;
-	    Ok(l)
+	    let mut parser = std::mem::take(&mut self.parser);
+	    parser.advance(self, buf);
+	    self.parser = parser;
+	    Ok(buf.len())
 	}
     }
 }
-
 #[derive(Debug)]
 enum EscASCII {
     None,
@@ -193,22 +165,22 @@ enum EscASCII {
     HT,
     LF,
     CR,
-    DEL,
 }
 trait WriteASCII {
     fn write_ascii(&mut self, esc: EscASCII);
 }
 impl<T: FnMut(Option<usize>)> WriteASCII for State<'_, T> {
     fn write_ascii(&mut self, esc: EscASCII) {
+	if let Some(trace) = &mut self.trace {trace.log("ascii", format_args!("{:?}", esc))};
 	match esc {
-	    EscASCII::None | EscASCII::BEL | EscASCII::DEL => (),
+	    EscASCII::None | EscASCII::BEL => (),
 	    EscASCII::BS => if self.col > 0 {
 		self.col -= 1;
 		self.del_trail();
 		self.draw(Some(self.lines.cur()));
 	    },
 	    EscASCII::HT => {
-		self.col = ((self.col>>3)+1)<<3;
+		self.col = cmp::min(((self.col>>3)+1)<<3, MAX_COLS);
 		self.trail();
 		self.draw(Some(self.lines.cur()));
 	    },
@@ -218,7 +190,13 @@ impl<T: FnMut(Option<usize>)> WriteASCII for State<'_, T> {
 		let cur = self.lines.cur() +
 		    if self.lines.cur() == self.lines.len()-1 {
 			self.draw(None);
-			self.lines.add(Vec::new())
+			if self.lines.len() >= self.lines.max {
+			    let top = std::mem::take(&mut self.lines[0]);
+			    self.scrollback.push(top, self.wrapped.contains(&0));
+			}
+			let added = self.lines.add_empty();
+			if added == 0 {self.scroll_rows(-1)};
+			added
 		    } else { 1 };
 		self.lines.set_cur(&mut self.draw, cur);
 		self.col = 0;
@@ -295,6 +273,7 @@ mod write_ascii_tests {
     }
 }
 
+#[derive(Debug)]
 enum EscCMD {
     None,
     CUU,
@@ -312,54 +291,222 @@ enum EscCMD {
     DSR,
     SGR,
     SET,
+    PushKKP,
+    PopKKP,
+    SetKKP,
+    QueryKKP,
+    DECSET,
+    DECRST,
+    DECRQM,
+    DECSCUSR,
+    DECDSR,
+    RIS,
+    DECSTR,
+    XTWINOPS,
+    WinMove,
+    WinResize,
+    WinResizePx,
+    DA1,
+    LineSize,
+    ICH,
+    DCH,
+    IL,
+    DL,
+    DECSLRM,
+    SCORC,
 }
+enum EscRect {
+    DECFRA,
+    DECERA,
+    DECSERA,
+    DECCRA,
+}
+enum EscDCS {
+    None,
+    Sixel,
+    XTGETTCAP,
+    DECRQSS,
+}
+// DCS string being received
+pub(super) enum Dcs {
+    Sixel(Sixel),
+    XTGETTCAP(Vec<u8>),   // hex encoded names separated by ;
+    DECRQSS(Vec<u8>),     // final and intermediate bytes of the setting
+}
+// longest query that is kept
+const MAX_DCS_QUERY: usize = 1024;
 trait WriteCMD {
     fn write_cmd(&mut self, esc: EscCMD, n: Option<u16>, m: Option<u16>);
     fn write_txt(&mut self, txt: &[u8]);
+    // OSC with a number handled in write_osc, bell: reply terminated with BEL instead of ST
+    fn write_osc(&mut self, cmd: u16, args: &[&[u8]], bell: bool);
+    // rectangular area operations, too many parameters for write_cmd
+    fn write_rect(&mut self, op: EscRect, params: &Params);
+    // DCS string, started by write_dcs with the parameters, its data comes in pieces
+    fn write_dcs(&mut self, dcs: EscDCS, params: &Params);
+    fn write_dcs_data(&mut self, data: &[u8]);
+    fn write_dcs_end(&mut self);
+    // sequences that are not handled, only for the trace
+    fn unknown(&mut self, _seq: fmt::Arguments) {}
+}
+impl<T: WriteCMD + WriteASCII> Perform for T {
+    fn print(&mut self, text: &[u8]) {
+	self.write_txt(text);
+    }
+    fn execute(&mut self, byte: u8) {
+	self.write_ascii(match byte {
+	    0x07 => EscASCII::BEL,
+	    0x08 => EscASCII::BS,
+	    0x09 => EscASCII::HT,
+	    0x0a..=0x0c | 0x84 | 0x85 => EscASCII::LF,   // VT, FF, IND and NEL like LF, there is no newline mode
+	    0x0d => EscASCII::CR,
+	    _ => EscASCII::None,
+	});
+    }
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: u8) {
+	let raw = intermediates;
+	let (private, intermediates) = match intermediates.first() {
+	    Some(p @ b'<'..=b'?') => (Some(*p), &intermediates[1..]),
+	    _ => (None, intermediates),
+	};
+	let (n, m) = (params.get(0), params.get(1));
+	let esc = match (private, intermediates, action) {
+	    (None, [], b'A') => EscCMD::CUU,
+	    (None, [], b'B') => EscCMD::CUD,
+	    (None, [], b'C') => EscCMD::CUF,
+	    (None, [], b'D') => EscCMD::CUB,
+	    (None, [], b'E') => EscCMD::CNL,
+	    (None, [], b'F') => EscCMD::CPL,
+	    (None, [], b'G') => EscCMD::CHA,
+	    (None, [], b'H' | b'f') => EscCMD::CUP,
+	    (None, [], b'J') => EscCMD::ED,
+	    (None, [], b'K') => EscCMD::EL,
+	    (None, [], b'S') => EscCMD::SU,
+	    (None, [], b'T') => EscCMD::SD,
+	    (None, [], b'@') => EscCMD::ICH,
+	    (None, [], b'P') => EscCMD::DCH,
+	    (None, [], b'L') => EscCMD::IL,
+	    (None, [], b'M') => EscCMD::DL,
+	    (None, [], b's') => EscCMD::DECSLRM,   // SCOSC unless DECLRMM is set
+	    (None, [], b'u') => EscCMD::SCORC,
+	    (None, [], b'n') if n == Some(6) => EscCMD::DSR,
+	    (None, [], b'm') => {   // every parameter is an attribute of its own
+		for attr in params.iter() {
+		    self.write_cmd(EscCMD::SGR, attr[0], None);
+		}
+		if params.is_empty() {self.write_cmd(EscCMD::SGR, None, None)};
+		return;
+	    },
+	    (None, [], b'~') => EscCMD::SET,
+	    (None, [b' '], b'q') => EscCMD::DECSCUSR,
+	    (None, [b'!'], b'p') => EscCMD::DECSTR,
+	    (None, [], b'c') if n.unwrap_or(0) == 0 => EscCMD::DA1,
+	    (None, [b'$'], b'x' | b'z' | b'{' | b'v') if !ignore => {
+		self.write_rect(match action {
+		    b'x' => EscRect::DECFRA,
+		    b'z' => EscRect::DECERA,
+		    b'{' => EscRect::DECSERA,
+		    _ => EscRect::DECCRA,
+		}, params);
+		return;
+	    },
+	    (None, [], b't') => {   // window operations with two arguments get them as n and m
+		let op = match n {
+		    Some(3) => EscCMD::WinMove,
+		    Some(4) => EscCMD::WinResizePx,
+		    Some(8) => EscCMD::WinResize,
+		    _ => EscCMD::XTWINOPS,
+		};
+		match op {
+		    EscCMD::XTWINOPS => self.write_cmd(op, n, m),
+		    _ => self.write_cmd(op, params.get(1), params.get(2)),
+		}
+		return;
+	    },
+	    (Some(b'>'), [], b'u') => EscCMD::PushKKP,
+	    (Some(b'<'), [], b'u') => EscCMD::PopKKP,
+	    (Some(b'='), [], b'u') => EscCMD::SetKKP,
+	    (Some(b'?'), [], b'u') => EscCMD::QueryKKP,
+	    (Some(b'?'), [], b'n') => EscCMD::DECDSR,
+	    (Some(b'?'), [], b'h' | b'l') => {   // any number of modes, two per call
+		let mut modes = params.iter().map(|mode| mode[0]);
+		while let Some(n) = modes.next() {
+		    self.write_cmd(if action == b'h' {EscCMD::DECSET} else {EscCMD::DECRST},
+				   n, modes.next().flatten());
+		}
+		return;
+	    },
+	    (Some(b'?'), [b'$'], b'p') => EscCMD::DECRQM,
+	    _ => EscCMD::None,
+	};
+	if ignore || matches!(esc, EscCMD::None) {
+	    let private = &raw[..raw.len()-intermediates.len()];
+	    self.unknown(format_args!("CSI {}{}{}{}", private.escape_ascii(), params,
+				      intermediates.escape_ascii(), (action as char).escape_default()));
+	}
+	if ignore {return};
+	self.write_cmd(esc, n, m);
+    }
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+	match (ignore, intermediates, byte) {
+	    (false, [], b'c') => self.write_cmd(EscCMD::RIS, None, None),
+	    (false, [b'#'], b'3'..=b'6') => self.write_cmd(EscCMD::LineSize, Some((byte-b'0') as u16), None),
+	    _ => self.unknown(format_args!("ESC {}{}", intermediates.escape_ascii(), (byte as char).escape_default())),
+	}
+    }
+    fn hook(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: u8) {
+	let dcs = match (ignore, intermediates, action) {
+	    (false, [], b'q') => EscDCS::Sixel,
+	    (false, [b'+'], b'q') => EscDCS::XTGETTCAP,
+	    (false, [b'$'], b'q') => EscDCS::DECRQSS,
+	    _ => {
+		self.unknown(format_args!("DCS {}{}{}", params, intermediates.escape_ascii(),
+					  (action as char).escape_default()));
+		EscDCS::None
+	    },
+	};
+	self.write_dcs(dcs, params);
+    }
+    fn put(&mut self, data: &[u8]) {
+	self.write_dcs_data(data);
+    }
+    fn unhook(&mut self) {
+	self.write_dcs_end();
+    }
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+	match parse_num(params[0]) {
+	    Some(cmd @ (4 | 10 | 11 | 12 | 104 | 110 | 111 | 112)) => self.write_osc(cmd, &params[1..], bell_terminated),
+	    _ => self.unknown(format_args!("OSC {}", OscParams(params))),
+	}
+    }
+}
+fn parse_num(arg: &[u8]) -> Option<u16> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
 }
-impl<T: WriteCMD> WriteANSI for T {
-    fn write_ansi(&mut self, esc_seq: & EscSeq) {
-	match esc_seq.esc {
-	    Esc::None => self.write_txt(&esc_seq.seq),
-	    Esc::CSI => {
-		println!("got CSI sequence: ESC {}",std::str::from_utf8(&esc_seq.seq).ok().unwrap());
-		let args: Vec<Option<u16>> = esc_seq.seq.split(|b: &u8| !(*b).is_ascii_digit())
-		    .map(|i: &[u8]| std::str::from_utf8(i)
-			 .expect("ascii digits should be valid utf8")
-			 .parse::<u16>().ok()  // only possible error should be for empty split
-		    ).take(2).collect();
-		self.write_cmd( match esc_seq.seq.last().expect(" CSI escape sequence should not be empty!") {
-		    b'A' => EscCMD::CUU,
-		    b'B' => EscCMD::CUD,
-		    b'C' => EscCMD::CUF,
-		    b'D' => EscCMD::CUB,
-		    b'E' => EscCMD::CNL,
-		    b'F' => EscCMD::CPL,
-		    b'G' => EscCMD::CHA,
-		    b'H' | b'f' => EscCMD::CUP,
-		    b'J' => EscCMD::ED,
-		    b'K' => EscCMD::EL,
-		    b'S' => EscCMD::SU,
-		    b'T' => EscCMD::SD,
-		    b'n' if args[0]==Some(6) => EscCMD::DSR,
-		    b'm' => EscCMD::SGR,
-		    b'~' => EscCMD::SET,
-		    _ => EscCMD::None,
-		}, args[0], args[1]);
-	    },
-	    Esc::NF | Esc::STT => (),
-	    Esc::Esc => panic!("read_ansi should not be called with Esc type Esc!"),
+struct OscParams<'a>(&'a [&'a [u8]]);
+impl fmt::Display for OscParams<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	for (i, p) in self.0.iter().enumerate() {
+	    if i > 0 {f.write_str(";")?};
+	    write!(f, "{}", p.escape_ascii())?;
 	}
+	Ok(())
     }
 }
 
 #[cfg(test)]
-mod write_ansi_test {
+mod perform_test {
     use super::*;
-    struct WriteANSITest {
+    use crate::t::parse::Parser;
+    struct PerformTest {
 	res: String,
     }
-    impl WriteCMD for WriteANSITest {
+    impl WriteASCII for PerformTest {
+	fn write_ascii(&mut self, esc: EscASCII) {
+	    self.res += &format!("<{:?}>", esc);
+	}
+    }
+    impl WriteCMD for PerformTest {
 	fn write_cmd(&mut self, esc: EscCMD, n: Option<u16>, m: Option<u16>) {
 	    self.res += match esc {
 		EscCMD::None => "<None",
@@ -378,6 +525,29 @@ mod write_ansi_test {
 		EscCMD::DSR => "<DSR",
 		EscCMD::SGR => "<SGR",
 		EscCMD::SET => "<SET",
+		EscCMD::PushKKP => "<PushKKP",
+		EscCMD::PopKKP => "<PopKKP",
+		EscCMD::SetKKP => "<SetKKP",
+		EscCMD::QueryKKP => "<QueryKKP",
+		EscCMD::DECSET => "<DECSET",
+		EscCMD::DECRST => "<DECRST",
+		EscCMD::DECRQM => "<DECRQM",
+		EscCMD::DECSCUSR => "<DECSCUSR",
+		EscCMD::DECDSR => "<DECDSR",
+		EscCMD::RIS => "<RIS",
+		EscCMD::DECSTR => "<DECSTR",
+		EscCMD::XTWINOPS => "<XTWINOPS",
+		EscCMD::WinMove => "<WinMove",
+		EscCMD::WinResize => "<WinResize",
+		EscCMD::WinResizePx => "<WinResizePx",
+		EscCMD::DA1 => "<DA1",
+		EscCMD::LineSize => "<LineSize",
+		EscCMD::ICH => "<ICH",
+		EscCMD::DCH => "<DCH",
+		EscCMD::IL => "<IL",
+		EscCMD::DL => "<DL",
+		EscCMD::DECSLRM => "<DECSLRM",
+		EscCMD::SCORC => "<SCORC",
 	    };
 	    if let Some(n) = n {self.res += &format!("{}",n)[..];}
 	    self.res += ";";
@@ -387,40 +557,72 @@ mod write_ansi_test {
 	fn write_txt(&mut self, txt: &[u8]) {
 	    self.res += std::str::from_utf8(txt).unwrap_or("invalid utf8");
 	}
+	fn write_osc(&mut self, cmd: u16, args: &[&[u8]], bell: bool) {
+	    let args: Vec<_> = args.iter().map(|a| String::from_utf8_lossy(a)).collect();
+	    self.res += &format!("<OSC{}:{}{}>", cmd, args.join(";"), if bell {";BEL"} else {""});
+	}
+	fn write_rect(&mut self, op: EscRect, params: &Params) {
+	    self.res += &format!("<{}:{}>", match op {
+		EscRect::DECFRA => "DECFRA",
+		EscRect::DECERA => "DECERA",
+		EscRect::DECSERA => "DECSERA",
+		EscRect::DECCRA => "DECCRA",
+	    }, params);
+	}
+	fn write_dcs(&mut self, dcs: EscDCS, params: &Params) {
+	    self.res += &format!("<DCS{}:{}>", match dcs {
+		EscDCS::None => "None",
+		EscDCS::Sixel => "Sixel",
+		EscDCS::XTGETTCAP => "XTGETTCAP",
+		EscDCS::DECRQSS => "DECRQSS",
+	    }, params);
+	}
+	fn write_dcs_data(&mut self, data: &[u8]) {
+	    self.res += std::str::from_utf8(data).unwrap_or("invalid utf8");
+	}
+	fn write_dcs_end(&mut self) {
+	    self.res += "<DCS end>";
+	}
+    }
+    fn perform(input: &str) -> String {
+	let mut test = PerformTest {res: String::new(),};
+	Parser::new().advance(&mut test, input.as_bytes());
+	test.res
     }
     #[test]
     fn basic_text() {
-	let mut test = WriteANSITest {res: String::new(),};
-	test.write_ansi(&EscSeq {
-	    esc: Esc::None,
-	    seq: Vec::from("text".as_bytes()),
-	    inv: false,
-	});
-	assert_eq!(test.res,"text");
+	assert_eq!(perform("text"),"text");
     }
-    impl EscSeq {
-	fn set(&mut self,val: &str) -> &Self {
-	    self.seq = Vec::from(val.as_bytes());
-	    self
-	}
+    #[test]
+    fn ascii() {
+	assert_eq!(perform("\x01\x07\x08\x09\x0a\x0b\x0c\x0d\x7f\x1bE\u{84}"),
+		   "<None><BEL><BS><HT><LF><LF><LF><CR><LF><LF>");
     }
     #[test]
     fn csi() {
-	let mut test = WriteANSITest {res: String::new(),};
-	let mut esc = EscSeq {
-	    esc: Esc::CSI,
-	    seq: Vec::from("15A".as_bytes()),
-	    inv: false,
-	};
-	test.write_ansi(&esc);
-	test.write_ansi(esc.set("3;B"));
-	test.write_ansi(esc.set("C"));
-	test.write_ansi(esc.set("3;4H"));
-	test.write_ansi(esc.set("f"));
-	test.write_ansi(esc.set("6n"));
-	test.write_ansi(esc.set("n"));
-	test.write_ansi(esc.set("99999~"));
-	assert_eq!(test.res,"<CUU15;><CUD3;><CUF;><CUP3;4><CUP;><DSR6;><None;><SET;>");
+	assert_eq!(perform("\x1b[15A\x1b[3;B\x1b[C\x1b[3;4H\x1b[f\x1b[6n\x1b[n\x1b[99999~\x1b[4 q\x1b[4q\x1b[!p"),
+		   "<CUU15;><CUD3;><CUF;><CUP3;4><CUP;><DSR6;><None;><SET65535;><DECSCUSR4;><None4;><DECSTR;>");
+	assert_eq!(perform("\x1b[18t\x1b[8;24;80t\x1b[3;;5t\x1b[4;1t"),
+		   "<XTWINOPS18;><WinResize24;80><WinMove;5><WinResizePx1;>");
+	assert_eq!(perform("\x1b[c\x1b[0c\x1b[1c\x1bP0;1q#0!3~\x1b\\\x1bP1$qm\u{9c}\x1bP+q544E\x1b\\\x1bP1!q\x1b\\"),
+		   "<DA1;><DA10;><None1;><DCSSixel:0;1>#0!3~<DCS end><DCSDECRQSS:1>m<DCS end>\
+		    <DCSXTGETTCAP:>544E<DCS end><DCSNone:1><DCS end>");
+	assert_eq!(perform("\x1bc"), "<RIS;>");
+	assert_eq!(perform("\x1b[2@\x1b[P\x1b[3L\x1b[M\x1b[s\x1b[2;9s\x1b[u\x1b[>1u"),
+		   "<ICH2;><DCH;><IL3;><DL;><DECSLRM;><DECSLRM2;9><SCORC;><PushKKP1;>");
+	assert_eq!(perform("\x1b[35;1;2;3;4$x\x1b[$z\x1b[1;2${\x1b[1;1;2;2;1;5;5$v\x1b[?1$x"),
+		   "<DECFRA:35;1;2;3;4><DECERA:><DECSERA:1;2><DECCRA:1;1;2;2;1;5;5><None1;>");
+	assert_eq!(perform("\x1b#3\x1b#6\x1b#8"), "<LineSize3;><LineSize6;>");
+	assert_eq!(perform("\x1b]11;?\x07\x1b]4;1;?;2;#f00\x1b\\\x1b]104\x07\x1b]1;x\x07"),
+		   "<OSC11:?;BEL><OSC4:1;?;2;#f00><OSC104:;BEL>");
+	assert_eq!(perform("\x1b[m\x1b[1;45;4:3m"), "<SGR;><SGR1;><SGR45;><SGR4;>");
+    }
+    #[test]
+    fn csi_private() {
+	assert_eq!(perform("\x1b[>5u\x1b[<u\x1b[=3;2u\x1b[?u\x1b[?1A\x1b[?2026;25h\x1b[?2026l\
+			    \x1b[?2026$p\x1b[?2026p\x1b[?996n\x1b[?1;2;3h"),
+		   "<PushKKP5;><PopKKP;><SetKKP3;2><QueryKKP;><None1;>\
+		    <DECSET2026;25><DECRST2026;><DECRQM2026;><None2026;><DECDSR996;><DECSET1;2><DECSET3;>");
     }
 }
 
@@ -575,6 +777,212 @@ mod write_cmd_tests {
 	assert_eq!(draw_text, "<Some(0)><None><None><None><None><None><None><Some(3)><None>");
     }
     #[test]
+    fn kkp() {
+	draw_test(|state| {
+	    state.write_all("\x1b[?u\x1b[>1u\x1b[>11u\x1b[?u".as_bytes());
+	    assert_eq!(state.keyboard_flags(), 11);
+	    state.write_all("\x1b[=4;2u\x1b[=1;3u".as_bytes());
+	    assert_eq!(state.keyboard_flags(), 14);
+	    state.write_all("\x1b[<u".as_bytes());
+	    assert_eq!(state.keyboard_flags(), 1);
+	    state.write_all("\x1b[<5u".as_bytes());
+	    assert_eq!(state.keyboard_flags(), 0);
+	    assert_eq!(state.take_reply(), b"\x1b[?0u\x1b[?11u");
+	});
+    }
+    #[test]
+    fn decscnm() {
+	let draw_text = draw_test(|state| {
+	    state.write_all("\x1b[?996n\x1b[?2031h\x1b[?5h".as_bytes());  // <None>
+	    assert!(state.dark());
+	    state.write_all("\x1b[?5h\x1b[?5$p".as_bytes());
+	    state.config.dark = true;
+	    state.write_all("\x1b[?996n".as_bytes());
+	    assert_eq!(state.take_reply(), b"\x1b[?997;2n\x1b[?997;1n\x1b[?5;1$y\x1b[?997;2n");
+	});
+	assert_eq!(draw_text, "<None>");
+    }
+    #[test]
+    fn xtwinops() {
+	let draw_text = draw_test(|state| {
+	    state.set_cell_size(6, 16);
+	    state.write_all(b"\x1b[18t\x1b[14t\x1b[16t\x1b[19t\x1b[8;10;40t\x1b[3;5;7t");
+	    assert_eq!(state.take_reply(), b"\x1b[8;24;50t\x1b[4;384;300t\x1b[6;16;6t");
+	    assert_eq!((state.lines.max, state.config.line_width), (24, 50));
+	    state.config.window_ops = true;
+	    state.write_all(b"\x1b[8;10;40t\x1b[4;;120t\x1b[3;5;7t\x1b[18t");  // <None><None><None>
+	    assert_eq!((state.lines.max, state.config.line_width), (10, 20));
+	    assert_eq!((state.config.pos.x_offset, state.config.pos.y_offset, state.config.pos.bottom), (5, 7, false));
+	    assert_eq!(state.take_reply(), b"\x1b[8;10;20t");
+	    state.set_screen_size(1000, 800);
+	    state.write_all(b"\x1b[13t");
+	    assert_eq!(state.take_reply(), b"\x1b[3;5;7t");
+	    state.config.pos.bottom = true;
+	    state.write_all(b"\x1b[13t");   // 1 line of 16 pixels and a 3 pixel border
+	    assert_eq!(state.take_reply(), b"\x1b[3;5;774t");
+	});
+	assert_eq!(draw_text, "<None><None><None>");
+    }
+    #[test]
+    fn resize_rows() {
+	let draw_text = draw_test(|state| {
+	    state.config.window_ops = true;
+	    state.write_all(b"1\r\n2\r\n3\r\n4\r\n5\x1b[8;2t");
+	    assert_eq!((state.scrollback.len(), &state.scrollback[0][..], &state.scrollback[2][..]), (3, &b"1"[..], &b"3"[..]));
+	    assert_eq!((state.lines.len(), &state.lines[0][..], &state.lines[1][..]), (2, &b"4"[..], &b"5"[..]));
+	    assert_eq!((state.lines.cur(), state.col), (1, 1));
+	    state.write_all(b"\x1b[1;1H\x1b[8;1t");   // rows below the cursor are cut
+	    assert_eq!((state.scrollback.len(), state.lines.len(), &state.lines[0][..]), (3, 1, &b"4"[..]));
+	});
+	assert!(draw_text.ends_with("<Some(0)><None>"));
+    }
+    #[test]
+    fn osc_colors() {
+	let draw_text = draw_test(|state| {
+	    state.write_all(b"\x1b]11;?\x07\x1b]4;1;?\x1b\\\x1b]10;#102030;rgb:f/f/f\x07");  // <None><None>
+	    assert_eq!(state.fg_bg(), (0x102030, 0xffffff));
+	    state.write_all(b"\x1b]4;1;#00ff00;196;?\x07\x1b]12;?\x07\x1b[?5h\x1b]11;?\x07");  // <None><None>
+	    assert_eq!(state.palette(1), 0x00ff00);
+	    state.write_all(b"\x1b]104;1\x07\x1b]110\x07\x1b[?5l\x1b]111\x07");  // <None><None><None><None>
+	    assert_eq!(state.palette(1), 0xcd0000);
+	    assert_eq!(state.fg_bg(), (0x000000, 0xffffff));
+	    assert_eq!(String::from_utf8(state.take_reply()).unwrap(),
+		       "\x1b]11;rgb:ffff/ffff/ffff\x07\x1b]4;1;rgb:cdcd/0000/0000\x1b\\\x1b]4;196;rgb:ffff/0000/0000\x07\
+			\x1b]12;rgb:1010/2020/3030\x07\x1b]11;rgb:1010/2020/3030\x07");
+	});
+	assert_eq!(draw_text, "<None><None><None><None><None><None><None><None>");
+    }
+    #[test]
+    fn sixel() {
+	draw_test(|state| {
+	    state.set_cell_size(1, 4);
+	    state.write_all(b"ab\x1bPq#0;2;100;0;0~~-~~\x1b\\c\x1b[c");  // 2x12 pixels, 3 lines
+	    assert_eq!(state.images.len(), 1);
+	    assert_eq!((state.images[0].row, state.images[0].col, state.images[0].rows), (0, 2, 3));
+	    assert_eq!(&state.images[0].image.rgba[..4], &[255, 0, 0, 255]);
+	    assert_eq!(&state.lines[0][..], b"ab\0\0");
+	    assert_eq!(&state.lines[2][..], b"  \0\0");
+	    assert_eq!((&state.lines[3][..], state.lines.cur()), (&b"c"[..], 3));
+	    assert_eq!(state.take_reply(), b"\x1b[?62;4;28c");
+	    state.write_all(b"\x1b[2T");
+	    assert_eq!(state.images[0].row, 2);
+	    state.write_all(b"\x1b[3S");
+	    assert!(state.images.is_empty());
+	    state.config.image_memory = 2*12*4*2;
+	    for _ in 0..3 {
+		state.write_all(b"\x1bP0;1q~~-~~\x1b\\");
+	    }
+	    assert_eq!(state.images.len(), 2);
+	    for _ in 0..24 {
+		state.write_all(b"\n");
+	    }
+	    assert!(state.images.is_empty());
+	});
+    }
+    #[test]
+    fn dcs_queries() {
+	draw_test(|state| {
+	    state.write_all(b"\x1bP+q544E;5463\x1b\\");
+	    assert_eq!(state.take_reply(), b"\x1bP1+r544E=7365612D7466\x1b\\\x1bP0+r5463\x1b\\");
+	    state.write_all(b"\x1b[45m\x1bP$qm\x1b\\\x1b[4 q\x1bP$q q\x1b\\\x1bP$qr\x1b\\\x1bP$qx\x1b\\");
+	    assert_eq!(state.take_reply(), b"\x1bP1$r0;47m\x1b\\\x1bP1$r4 q\x1b\\\x1bP1$r1;24r\x1b\\\x1bP0$r\x1b\\");
+	    let long = [b'6'; 2*MAX_DCS_QUERY];
+	    state.write_all(b"\x1bP+q").unwrap();
+	    state.write_all(&long).unwrap();
+	    state.write_all(b"\x1b\\").unwrap();
+	    assert_eq!(state.take_reply().len(), MAX_DCS_QUERY+7);
+	});
+    }
+    #[test]
+    fn line_size() {
+	draw_test(|state| {
+	    state.write_all(b"\x1b#6a\n\x1b#3b\n\x1b#4b\x1b#6\n\x1b#5c");
+	    assert_eq!(state.line_size(0), Some(LineSize::DoubleWidth));
+	    assert_eq!(state.line_size(1), Some(LineSize::DoubleTop));
+	    assert_eq!(state.line_size(2), Some(LineSize::DoubleWidth));
+	    assert_eq!(state.line_size(3), None);
+	    state.write_all(b"\x1b[T");
+	    assert_eq!((state.line_size(0), state.line_size(1)), (None, Some(LineSize::DoubleWidth)));
+	    state.write_all(b"\x1b[2S");
+	    assert_eq!((state.line_size(0), state.line_size(1)), (Some(LineSize::DoubleTop), Some(LineSize::DoubleWidth)));
+	    state.write_all(b"\x1b[2J");
+	    assert_eq!(state.line_size(0), None);
+	    state.write_all(format!("\x1b[H\x1b#6{}", "x".repeat(30)).as_bytes());   // wraps at the visible edge
+	    assert_eq!((state.lines[0].len(), state.lines[1].len(), state.lines.cur(), state.col), (25, 5, 1, 5));
+	    assert_eq!((state.wrapped.clone(), state.line_size(1)), (vec![0], None));
+	});
+    }
+    #[test]
+    fn rectangles() {
+	draw_test(|state| {
+	    state.write_all(b"abcdef\nghijkl\nx");
+	    state.write_all(b"\x1b[42;1;2;2;3$x");
+	    assert_eq!((&state.lines[0][..], &state.lines[1][..]), (&b"a**def"[..], &b"g**jkl"[..]));
+	    state.write_all(b"\x1b[1;1;2;3;1;2;4$v");
+	    assert_eq!((&state.lines[1][..], &state.lines[2][..]), (&b"g**a**"[..], &b"x  g**"[..]));
+	    state.write_all(b"\x1b[2;5;3;100$z");
+	    assert_eq!((&state.lines[1][..], &state.lines[2][..]), (&b"g**a"[..], &b"x  g"[..]));
+	    state.write_all(b"\x1b[3;1;4$x\x1b[3;3;2;1$z");
+	    assert_eq!(state.lines.len(), 3);
+	    state.write_all(b"\x1b[35;5;2;5;3$x");
+	    assert_eq!((state.lines.len(), &state.lines[4][..], state.lines.end), (5, &b" ##"[..], 5));
+	    state.write_all(b"\x1b[1;1;5;50${");
+	    assert!(state.lines[0].is_empty() && state.lines[4].is_empty());
+	});
+    }
+    #[test]
+    fn margins() {
+	draw_test(|state| {
+	    state.write_all(b"abcdef\nghijkl\x1b[2;3H\x1b[s\x1b[H\x1b[u\x1b[2@");
+	    assert_eq!((state.lines.cur(), state.col, &state.lines[1][..]), (1, 2, &b"gh  ijkl"[..]));
+	    state.write_all(b"\x1b[3P");
+	    assert_eq!(&state.lines[1][..], b"ghjkl");
+	    state.write_all(b"\x1b[?69h\x1b[2;4s\x1b[?69$p\x1bP$qs\x1b\\");
+	    assert_eq!((state.lines.cur(), state.col), (0, 0));
+	    assert_eq!(state.take_reply(), b"\x1b[?69;1$y\x1bP1$r2;4s\x1b\\");
+	    state.write_all(b"\x1b[1;2H\x1b[@");
+	    assert_eq!(&state.lines[0][..], b"a bcef");
+	    state.write_all(b"\x1b[2P");
+	    assert_eq!(&state.lines[0][..], b"ac  ef");
+	    state.write_all(b"\x1b[S");
+	    assert_eq!((&state.lines[0][..], &state.lines[1][..]), (&b"ahjkef"[..], &b"g   l"[..]));
+	    state.write_all(b"\x1b[T\x1b[2;3H\x1b[L");
+	    assert_eq!((&state.lines[0][..], &state.lines[1][..], &state.lines[2][..]), (&b"a   ef"[..], &b"g   l"[..], &b" hjk"[..]));
+	    assert_eq!(state.col, 1);
+	    state.write_all(b"\x1b[M\x1b[?69l\x1b[s\x1b[1;3H\x1b[u");
+	    assert_eq!((state.lines.cur(), state.col), (1, 1));
+	    assert_eq!(&state.lines[1][..], b"ghjkl");
+	    state.write_all(b"\x1b[H\x1b[L");
+	    assert_eq!((state.lines[0].is_empty(), &state.lines[1][..], &state.lines[2][..]), (true, &b"a   ef"[..], &b"ghjkl"[..]));
+	    state.write_all(b"\x1b[2M");
+	    assert_eq!((&state.lines[0][..], state.lines[1].is_empty(), state.lines.len()), (&b"ghjkl"[..], true, 4));
+	});
+    }
+    #[test]
+    fn wide() {
+	draw_test(|state| {
+	    state.write_all("a漢e\u{301}😀b".as_bytes());
+	    assert_eq!((&state.lines[0][..], state.col), (&b"a?\x01??\x01b"[..], 7));
+	    state.write_all(b"\x1b[3Gx\x1b[6Gy");
+	    assert_eq!(&state.lines[0][..], b"a x? yb");
+	    state.write_all("\x1b[4G漢\x1b[2G°".as_bytes());
+	    assert_eq!((&state.lines[0][..], state.col), (&b"a?x?\x01yb"[..], 2));
+	    state.config.ambiguous_wide = true;
+	    state.write_all("\x1b[?69h\x1b[1;4s\x1b[2G°°".as_bytes());
+	    assert_eq!((&state.lines[0][..], state.col), (&b"a ?\x01 yb"[..], 3));
+	});
+    }
+    #[test]
+    fn combining() {
+	draw_test(|state| {
+	    state.write_all(b"ae");
+	    state.write_all("\u{301}漢\u{308}".as_bytes());
+	    assert_eq!((&state.lines[0][..], state.col), (&b"a??\x01"[..], 4));
+	    state.write_all("\r\n\u{301}x".as_bytes());
+	    assert_eq!((&state.lines[1][..], state.col), (&b"?x"[..], 2));
+	});
+    }
+    #[test]
     fn sgr() {
 	draw_test(|state| {
 	    state.write_all("hi \x1b[45m hi \x1b[49m hi".as_bytes());
@@ -585,6 +993,9 @@ mod write_cmd_tests {
 
 impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
     fn write_cmd(&mut self, esc: EscCMD, n: Option<u16>, m: Option<u16>) {
+	if let (Some(trace), false) = (&mut self.trace, matches!(esc, EscCMD::None)) {
+	    trace.log("cmd", format_args!("{:?} {:?} {:?}", esc, n, m));
+	}
 	match esc {
 	    EscCMD::None => (),
 	    EscCMD::CUU => {
@@ -604,7 +1015,7 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		self.draw(Some(self.lines.cur()));
 	    },
 	    EscCMD::CUF => {
-		self.col += <u16 as Into<usize>>::into(n.unwrap_or(1));
+		self.col = cmp::min(self.col + <usize as From<u16>>::from(n.unwrap_or(1)), MAX_COLS);
 		self.draw(Some(self.lines.cur()));
 		self.trail();
 	    },
@@ -657,15 +1068,18 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 			}
 			self.lines.get().truncate(col);
 			self.lines.end = self.lines.cur()+1;
+			let cur = self.lines.cur();
+			self.retain_rows(|row| row <= cur);
 		    },
 		    1 => {
 			for i in 0..self.lines.cur() {
 			    self.lines[i].clear();
 			}
-			self.lines.get().get_mut(0..col)
-			    .expect("0..cur should be valid index on current line!")
-			    .fill(b' ');
+			self.trail();
+			self.lines.get()[..col].fill(b' ');
 			self.lines.start = self.lines.cur();
+			let cur = self.lines.cur();
+			self.retain_rows(|row| row >= cur);
 		    },
 		    2 => {
 			for i in 0..self.lines.len() {
@@ -674,7 +1088,9 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 			self.trail();
 			self.lines.start = self.lines.cur();
 			self.lines.end = self.lines.cur()+1;
+			self.retain_rows(|_| false);
 		    },
+		    3 => self.scrollback.clear(),
 		    _ => (),
 		};
 		self.draw(None);
@@ -683,9 +1099,10 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		let col = self.col;
 		match n.unwrap_or(0) {
 		    0 => self.lines.get().truncate(col),
-		    1 => self.lines.get().get_mut(..col)
-			.expect("0..cur should be valid index for current line!")
-			.fill(b' '),
+		    1 => {
+			self.trail();
+			self.lines.get()[..col].fill(b' ');
+		    },
 		    2 => {
 			self.lines.get().clear();
 			self.trail();
@@ -694,11 +1111,24 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		};
 		self.draw(Some(self.lines.cur()));
 	    },
+	    EscCMD::SU if self.margins().is_some() => {
+		let len = self.lines.len();
+		self.scroll_margins(0, len, n.unwrap_or(1) as isize);
+	    },
+	    EscCMD::SD if self.margins().is_some() => {
+		let n: usize = n.unwrap_or(1).into();
+		let bottom = cmp::min(self.lines.max, self.lines.len().saturating_add(n));
+		self.scroll_margins(0, bottom, -(n as isize));
+	    },
 	    EscCMD::SU => {
 		let n = cmp::min(n.unwrap_or(1).into(),self.lines.len());
 		self.del_trail();
-		for i in 0..n {self.lines[i].clear();}
+		for i in 0..n {
+		    let top = std::mem::take(&mut self.lines[i]);
+		    self.scrollback.push(top, self.wrapped.contains(&i));
+		}
 		self.lines.inc_ofs(n);
+		self.scroll_rows(-(n as isize));
 		self.lines.start -= cmp::min(self.lines.start,n);
 		while self.lines[self.lines.start].is_empty() && self.lines.start < self.lines.cur() {
 		    self.lines.start += 1;
@@ -712,6 +1142,7 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		let n = cmp::min(n.unwrap_or(1).into(),l);
 		for i in l-n..l {self.lines[i].clear();}
 		self.lines.dec_ofs(n);
+		self.scroll_rows(n as isize);
 		self.lines.start += cmp::min(n,self.lines.cur()-self.lines.start);
 		self.lines.end = cmp::min(self.lines.end+n,self.lines.len());
 		while self.lines[self.lines.end].is_empty() && self.lines.end > self.lines.cur()+1 {
@@ -720,22 +1151,104 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		self.trail();
 		self.draw(None);
 	    },
+	    EscCMD::ICH | EscCMD::DCH => {
+		let (n, col) = (cmp::max(n.unwrap_or(1), 1) as usize, self.col);
+		self.trail();
+		match self.margins() {
+		    Some((left, right)) if col < left || col >= right => return,
+		    Some((_, right)) => {   // characters are pushed out at the right margin
+			let n = cmp::min(n, right-col);
+			let l = self.lines.get();
+			if l.len() < right {l.resize(right, b' ')};
+			if matches!(esc, EscCMD::ICH) {
+			    l.copy_within(col..right-n, col+n);
+			    l[col..col+n].fill(b' ');
+			} else {
+			    l.copy_within(col+n..right, col);
+			    l[right-n..right].fill(b' ');
+			}
+		    },
+		    None => {
+			let l = self.lines.get();
+			if matches!(esc, EscCMD::ICH) {
+			    let n = cmp::min(n, MAX_COLS.saturating_sub(l.len()));
+			    l.splice(col..col, std::iter::repeat(b' ').take(n));
+			} else {
+			    l.drain(col..cmp::min(col.saturating_add(n), l.len()));
+			}
+		    },
+		}
+		let cur = self.lines.cur();
+		self.trim_line(cur);
+		self.draw(Some(cur));
+	    },
+	    EscCMD::IL | EscCMD::DL => {   // lines from the cursor down to the bottom of the page
+		let (n, cur) = (cmp::max(n.unwrap_or(1), 1) as usize, self.lines.cur());
+		let insert = matches!(esc, EscCMD::IL);
+		match self.margins() {
+		    Some((left, right)) if self.col < left || self.col >= right => return,
+		    Some((left, _)) => {
+			let bottom = if insert {cmp::min(self.lines.max, self.lines.len().saturating_add(n))}
+			else {self.lines.len()};
+			self.scroll_margins(cur, bottom, if insert {-(n as isize)} else {n as isize});
+			self.col = left;
+		    },
+		    None => {
+			let visible = (self.lines.start, self.lines.end);
+			if insert {
+			    let n = cmp::min(n, self.lines.max-cur);
+			    let len = cmp::min(self.lines.len()+n, self.lines.max);
+			    while self.lines.len() < len {
+				self.lines.add(Vec::new());
+			    }
+			    for row in (cur+n..self.lines.len()).rev() {self.lines.swap(row, row-n)};
+			    for row in cur..cur+n {self.lines[row].clear()};
+			} else {
+			    let (len, n) = (self.lines.len(), cmp::min(n, self.lines.len()-cur));
+			    for row in cur..len-n {self.lines.swap(row, row+n)};
+			    for row in len-n..len {self.lines[row].clear()};
+			}
+			self.retain_rows(|row| row < cur);
+			self.col = 0;
+			self.lines.end = cmp::max(self.lines.end, cur+1);
+			while self.lines.end > cur+1 && self.lines[self.lines.end-1].is_empty() {self.lines.end -= 1};
+			let len = self.lines.len();
+			self.draw_rows(cur..len, visible);
+		    },
+		}
+		self.trail();
+	    },
+	    EscCMD::DECSLRM if self.modes.lr_margins => {   // 1 and page width by default, cursor goes home
+		let cols = self.config.line_width as usize;
+		let left = one_to_zero(n);
+		let right = m.filter(|m| *m > 0).map_or(cols, |m| cmp::min(m as usize, cols));
+		if left+1 >= right {return};
+		self.modes.margins = Some((left, right));
+		self.write_cmd(EscCMD::CUP, None, None);
+	    },
+	    EscCMD::DECSLRM => self.modes.saved_cursor = Some((self.lines.cur(), self.col)),
+	    EscCMD::SCORC => if let Some((row, col)) = self.modes.saved_cursor {
+		let (row, col) = ((row+1).try_into().unwrap_or(u16::MAX), (col+1).try_into().unwrap_or(u16::MAX));
+		self.write_cmd(EscCMD::CUP, Some(row), Some(col));
+	    },
 	    EscCMD::DSR => {
-		let _msg = format!("\x1b[{};{}R", self.lines.cur(), self.col);
+		let msg = format!("\x1b[{};{}R", self.lines.cur()+1, self.col+1);
+		self.reply.extend_from_slice(msg.as_bytes());
 	    },
 	    EscCMD::SGR => {
 		match n {
-		    Some(40..=48) => self.leftover.inv = true,
-		    Some(0) | Some(49) => self.leftover.inv = false,
+		    Some(40..=48) => self.inv = true,
+		    None | Some(0) | Some(49) => self.inv = false,
 		    _ => (),
 		}
 	    },
 	    EscCMD::SET => {   // CSI_n_;73~ to set max height to _n_, default 5
 		match m {
-		    Some(73) => {
+		    Some(73) if n != Some(0) => {
 			let len: u16 = n.unwrap_or(5);
 			if (len as usize) < self.lines.max {
 			    self.lines = QueueView::<Vec<u8>>::new(len);
+			    self.retain_rows(|_| false);
 			    self.col=0;
 			}
 			self.draw(None);
@@ -743,24 +1256,535 @@ impl<T: FnMut(Option<usize>)> WriteCMD for State<'_, T> {
 		    _ => (),
 		}
 	    },
+	    EscCMD::PushKKP => {
+		if self.keyboard.len() >= KKP_STACK_SIZE {
+		    self.keyboard.remove(0);
+		}
+		self.keyboard.push(n.unwrap_or(0) as u8 & KKP_FLAGS);
+	    },
+	    EscCMD::PopKKP => {
+		let n = cmp::min(self.keyboard.len(), n.unwrap_or(1).into());
+		self.keyboard.truncate(self.keyboard.len()-n);
+	    },
+	    EscCMD::SetKKP => {   // CSI = flags ; mode u, mode 1: replace, 2: set bits, 3: clear bits
+		let flags = n.unwrap_or(0) as u8 & KKP_FLAGS;
+		let old = self.keyboard_flags();
+		let new = match m.unwrap_or(1) {
+		    2 => old | flags,
+		    3 => old & !flags,
+		    _ => flags,
+		};
+		match self.keyboard.last_mut() {
+		    Some(top) => *top = new,
+		    None => self.keyboard.push(new),
+		}
+	    },
+	    EscCMD::QueryKKP => {
+		let msg = format!("\x1b[?{}u", self.keyboard_flags());
+		self.reply.extend_from_slice(msg.as_bytes());
+	    },
+	    EscCMD::DECSET | EscCMD::DECRST => {
+		let on = matches!(esc, EscCMD::DECSET);
+		for mode in [n, m].into_iter().flatten() {
+		    self.set_mode(mode, on);
+		}
+	    },
+	    EscCMD::DECRQM => {   // answer 1: set, 2: reset, 0: not recognized
+		let n = n.unwrap_or(0);
+		let msg = format!("\x1b[?{};{}$y", n, match self.mode(n) {
+		    Some(true) => 1,
+		    Some(false) => 2,
+		    None => 0,
+		});
+		self.reply.extend_from_slice(msg.as_bytes());
+	    },
+	    EscCMD::DECSCUSR => {   // 0: default, 1/2: block, 3/4: underline, 5/6: bar, odd ones blink
+		let shape = match n.unwrap_or(0) {
+		    0 => None,
+		    1 | 2 => Some(CursorShape::Block),
+		    3 | 4 => Some(CursorShape::Underline),
+		    5 | 6 => Some(CursorShape::Bar),
+		    _ => return,
+		};
+		self.modes.cursor_shape = shape;
+		self.modes.cursor_blink = shape.and(n.map(|n| n%2 == 1));
+		self.draw(Some(self.lines.cur()));
+	    },
+	    EscCMD::DECDSR => match n {
+		Some(6) => {
+		    let msg = format!("\x1b[?{};{}R", self.lines.cur()+1, self.col+1);
+		    self.reply.extend_from_slice(msg.as_bytes());
+		},
+		Some(996) => self.report_scheme(),
+		_ => (),
+	    },
+	    EscCMD::RIS => self.reset(),
+	    EscCMD::LineSize => {   // 3/4: top/bottom half of DECDHL, 5: DECSWL, 6: DECDWL
+		let cur = self.lines.cur();
+		self.line_sizes.retain(|(row, _)| *row != cur);
+		match n {
+		    Some(3) => self.line_sizes.push((cur, LineSize::DoubleTop)),
+		    Some(4) => self.line_sizes.push((cur, LineSize::DoubleBottom)),
+		    Some(6) => self.line_sizes.push((cur, LineSize::DoubleWidth)),
+		    _ => (),
+		}
+		self.draw(Some(cur));
+	    },
+	    EscCMD::DECSTR => self.soft_reset(),
+	    EscCMD::XTWINOPS => {   // reports, sizes as height;width
+		let (c_w, c_h) = self.cell_size;
+		let (rows, cols) = (self.lines.max as u16, self.config.line_width);
+		let msg = match n {
+		    Some(13) => {
+			let (width, height) = self.config.window_size(self.cell_size, self.lines.vis_len());
+			let (x, y) = self.config.pos.get_xy(width, height, self.screen_size);
+			format!("\x1b[3;{};{}t", x, y)
+		    },
+		    Some(14) => format!("\x1b[4;{};{}t", rows.saturating_mul(c_h), cols.saturating_mul(c_w)),
+		    Some(16) => format!("\x1b[6;{};{}t", c_h, c_w),
+		    Some(18) => format!("\x1b[8;{};{}t", rows, cols),
+		    _ => return,
+		};
+		self.reply.extend_from_slice(msg.as_bytes());
+	    },
+	    EscCMD::WinMove if self.config.window_ops => {
+		self.config.pos = WindowPosition {
+		    right: false,
+		    bottom: false,
+		    x_offset: n.unwrap_or(0),
+		    y_offset: m.unwrap_or(0),
+		};
+		self.draw(None);
+	    },
+	    EscCMD::WinResize if self.config.window_ops => self.resize(n, m),
+	    EscCMD::WinResizePx if self.config.window_ops => {
+		let (c_w, c_h) = self.cell_size;
+		if c_w > 0 && c_h > 0 {
+		    self.resize(n.map(|h| h/c_h), m.map(|w| w/c_w));
+		}
+	    },
+	    EscCMD::WinMove | EscCMD::WinResize | EscCMD::WinResizePx => (),
+	    EscCMD::DA1 => self.reply.extend_from_slice(b"\x1b[?62;4;28c"),   // VT220 with sixel graphics and rectangles
 	}
     }
     fn write_txt(&mut self, txt: &[u8]) {
-	let cp = &txt[..cmp::min( txt.len(), 0xffff-self.col )];
-	let col = self.col;
-	let l = self.lines.get();
-	if col+cp.len() >= l.len() {
-	    l.truncate(col);
-	    l.extend_from_slice(cp);
-	    
-	} else {
-	    let _ = &l[col..col+cp.len()].copy_from_slice(cp);
+	if let Some(trace) = &mut self.trace {trace.bytes("text", txt)};
+	let mut txt = txt;
+	while let Some(split) = self.wrap_point(txt) {   // the rest continues on the next row
+	    self.put_txt(&txt[..split]);
+	    let cur = self.lines.cur();
+	    if !self.wrapped.contains(&cur) {self.wrapped.push(cur)};
+	    self.write_ascii(EscASCII::LF);
+	    txt = &txt[split..];
 	}
-	self.col += cp.len();
-	self.draw(Some(self.lines.cur()));
+	self.put_txt(txt);
+    }
+    fn write_osc(&mut self, cmd: u16, args: &[&[u8]], bell: bool) {
+	if let Some(trace) = &mut self.trace {trace.log("osc", format_args!("{} {}", cmd, OscParams(args)))};
+	let st = if bell {"\x07"} else {"\x1b\\"};
+	match cmd {
+	    // OSC 4 ; index ; spec [; index ; spec ...], text has no colors, only sixel images use the palette
+	    4 => for pair in args.chunks(2) {
+		let (Some(i), Some(spec)) = (parse_num(pair[0]).and_then(|i| u8::try_from(i).ok()), pair.get(1))
+		else {continue};
+		if *spec == b"?" {
+		    let msg = format!("\x1b]4;{};{}{}", i, color::format(self.palette(i)), st);
+		    self.reply.extend_from_slice(msg.as_bytes());
+		} else if let Some(rgb) = color::parse(spec) {
+		    self.colors.palette[i as usize] = Some(rgb);
+		    self.draw(None);
+		}
+	    },
+	    10..=12 => {   // further arguments set the following colors like xterm
+		for (cmd, spec) in (cmd..=12).zip(args) {
+		    if *spec == b"?" {
+			let rgb = match cmd {
+			    10 => self.fg_bg().0,
+			    11 => self.fg_bg().1,
+			    _ => self.cursor_color(),
+			};
+			let msg = format!("\x1b]{};{}{}", cmd, color::format(rgb), st);
+			self.reply.extend_from_slice(msg.as_bytes());
+		    } else if let Some(rgb) = color::parse(spec) {
+			*self.dynamic_color(cmd) = Some(rgb);
+			self.draw(None);
+		    }
+		}
+	    },
+	    104 => {
+		if args.iter().all(|a| a.is_empty()) {
+		    self.colors.palette = [None; 256];
+		} else {
+		    for i in args.iter().filter_map(|a| parse_num(a)).filter(|i| *i < 256) {
+			self.colors.palette[i as usize] = None;
+		    }
+		}
+		self.draw(None);
+	    },
+	    110..=112 => {
+		*self.dynamic_color(cmd-100) = None;
+		self.draw(None);
+	    },
+	    _ => (),
+	}
+    }
+    fn write_rect(&mut self, op: EscRect, params: &Params) {
+	match op {
+	    EscRect::DECFRA => {
+		let c = match params.get(0) {
+		    Some(c @ 32..=126) => c as u8,
+		    Some(160..=255) => b'?',   // like any other non-ASCII character
+		    _ => return,
+		};
+		if let Some(rect) = self.rect(params, 1) {self.fill_rect(rect, Some(c))};
+	    },
+	    EscRect::DECERA | EscRect::DECSERA => {   // nothing is protected, selective erase is the same
+		if let Some(rect) = self.rect(params, 0) {self.fill_rect(rect, None)};
+	    },
+	    EscRect::DECCRA => {
+		let Some(rect) = self.rect(params, 0) else {return};
+		self.copy_rect(rect, one_to_zero(params.get(5)), one_to_zero(params.get(6)));
+	    },
+	}
+    }
+    fn write_dcs(&mut self, dcs: EscDCS, params: &Params) {
+	self.dcs = match dcs {
+	    EscDCS::Sixel => {
+		let palette = (0..=255).map(|i| self.palette(i)).collect();
+		Some(Dcs::Sixel(Sixel::new(params.get(1), palette, self.fg_bg().1, self.config.image_memory)))
+	    },
+	    EscDCS::XTGETTCAP => Some(Dcs::XTGETTCAP(Vec::new())),
+	    EscDCS::DECRQSS => Some(Dcs::DECRQSS(Vec::new())),
+	    EscDCS::None => None,
+	};
+    }
+    fn write_dcs_data(&mut self, data: &[u8]) {
+	match &mut self.dcs {
+	    Some(Dcs::Sixel(sixel)) => sixel.put(data),
+	    Some(Dcs::XTGETTCAP(query) | Dcs::DECRQSS(query)) => {
+		let n = cmp::min(data.len(), MAX_DCS_QUERY.saturating_sub(query.len()));
+		query.extend_from_slice(&data[..n]);
+	    },
+	    None => (),
+	}
+    }
+    fn write_dcs_end(&mut self) {
+	match self.dcs.take() {
+	    Some(Dcs::Sixel(sixel)) => if let Some(image) = sixel.finish() {
+		self.place_image(image);
+	    },
+	    Some(Dcs::XTGETTCAP(names)) => for name in names.split(|b| *b == b';') {
+		self.reply.extend_from_slice(&caps::xtgettcap(name));
+	    },
+	    Some(Dcs::DECRQSS(setting)) => self.decrqss(&setting),
+	    None => (),
+	}
+    }
+    fn unknown(&mut self, seq: fmt::Arguments) {
+	if let Some(trace) = &mut self.trace {trace.log("unknown", seq)};
     }
 }
 impl<T: FnMut(Option<usize>)> State<'_, T> {
+    fn set_mode(&mut self, mode: u16, on: bool) {
+	match mode {
+	    5 => if self.modes.reverse != on {
+		self.modes.reverse = on;
+		if self.modes.scheme_reports {self.report_scheme()};
+		self.draw(None);
+	    },
+	    12 => self.modes.cursor_blink = Some(on),
+	    25 => {
+		self.modes.cursor_visible = on;
+		self.draw(Some(self.lines.cur()));
+	    },
+	    2026 => self.modes.sync = if !on {None}
+	    else {Some(self.modes.sync.unwrap_or_else(Instant::now))},
+	    2031 => self.modes.scheme_reports = on,
+	    2004 => self.modes.bracketed_paste = on,
+	    7 => self.modes.autowrap = on,
+	    69 => {
+		self.modes.lr_margins = on;
+		self.modes.margins = None;
+	    },
+	    _ => (),
+	}
+    }
+    // top, left, bottom, right of the rectangle in params from first on, 0-based with bottom and right excluded,
+    // absolute as there is no origin mode (DECOM) to make them relative to the margins
+    fn rect(&self, params: &Params, first: usize) -> Option<(usize, usize, usize, usize)> {
+	let (rows, cols) = (self.lines.max, self.config.line_width as usize);
+	let end = |p: Option<u16>, max: usize| p.filter(|p| *p > 0).map_or(max, |p| cmp::min(p as usize, max));
+	let (top, left) = (one_to_zero(params.get(first)), one_to_zero(params.get(first+1)));
+	let (bottom, right) = (end(params.get(first+2), rows), end(params.get(first+3), cols));
+	if top < bottom && left < right {Some((top, left, bottom, right))} else {None}
+    }
+    // to dst_top, dst_left, clipped to the page
+    fn copy_rect(&mut self, (top, left, bottom, right): (usize, usize, usize, usize), dst_top: usize, dst_left: usize) {
+	let (rows, cols) = (self.lines.max, self.config.line_width as usize);
+	let visible = (self.lines.start, self.lines.end);
+	if dst_top >= rows || dst_left >= cols {return};
+	let bottom = cmp::min(bottom, top+rows-dst_top);
+	let right = cmp::min(right, left+cols-dst_left);
+	// copied first, source and destination may overlap
+	let src: Vec<Vec<u8>> = (top..bottom).map(|row| {
+	    let line: &[u8] = if row < self.lines.len() {&self.lines[row]} else {&[]};
+	    (left..right).map(|col| line.get(col).copied().unwrap_or(b' ')).collect()
+	}).collect();
+	let n = src.len();
+	for (i, cells) in src.into_iter().enumerate() {
+	    if dst_top+i >= self.lines.len() && cells.iter().all(|c| *c == b' ') {continue};   // already blank
+	    let l = self.grid_line(dst_top+i);
+	    if l.len() < dst_left+cells.len() {l.resize(dst_left+cells.len(), b' ')};
+	    l[dst_left..dst_left+cells.len()].copy_from_slice(&cells);
+	    self.trim_line(dst_top+i);
+	}
+	self.draw_rows(dst_top..dst_top+n, visible);
+    }
+    // left and right margin if DECLRMM is set, right excluded
+    fn margins(&self) -> Option<(usize, usize)> {
+	self.modes.margins.filter(|_| self.modes.lr_margins)
+    }
+    // columns between the margins of rows top..bottom moved up by n, down if negative
+    fn scroll_margins(&mut self, top: usize, bottom: usize, n: isize) {
+	let Some((left, right)) = self.margins() else {return};
+	if top >= bottom {return};
+	let k = cmp::min(n.unsigned_abs(), bottom-top);
+	if n > 0 {
+	    self.copy_rect((top+k, left, bottom, right), top, left);
+	    self.fill_rect((bottom-k, left, bottom, right), None);
+	} else {
+	    self.copy_rect((top, left, bottom-k, right), top+k, left);
+	    self.fill_rect((top, left, top+k, right), None);
+	}
+    }
+    // None erases
+    fn fill_rect(&mut self, (top, left, bottom, right): (usize, usize, usize, usize), c: Option<u8>) {
+	let visible = (self.lines.start, self.lines.end);
+	let bottom = if c.is_none() {cmp::min(bottom, self.lines.len())} else {bottom};   // missing lines are blank
+	for row in top..bottom {
+	    let l = self.grid_line(row);
+	    if l.len() < right {l.resize(right, b' ')};
+	    l[left..right].fill(c.unwrap_or(b' '));
+	    self.trim_line(row);
+	}
+	self.draw_rows(top..bottom, visible);
+    }
+    // everything if the visible lines changed from before
+    fn draw_rows(&mut self, rows: std::ops::Range<usize>, before: (usize, usize)) {
+	if before != (self.lines.start, self.lines.end) {return self.draw(None)};
+	for row in rows {
+	    self.draw(Some(row));
+	}
+    }
+    // line at row of the page, added if it does not exist yet
+    fn grid_line(&mut self, row: usize) -> &mut Vec<u8> {
+	while self.lines.len() <= row {
+	    self.lines.add(Vec::new());
+	}
+	self.lines.start = cmp::min(self.lines.start, row);
+	self.lines.end = cmp::max(self.lines.end, row+1);
+	&mut self.lines[row]
+    }
+    // without trailing spaces, the cursor line keeps those up to the cursor
+    fn trim_line(&mut self, row: usize) {
+	let keep = if row == self.lines.cur() {self.col} else {0};
+	let l = &mut self.lines[row];
+	let len = l.len()-l.iter().rev().take_while(|b| **b == b' ').count();
+	l.truncate(cmp::max(len, keep));
+    }
+    // DCS 1 $ r setting ST or DCS 0 $ r ST if it is not known
+    fn decrqss(&mut self, setting: &[u8]) {
+	let value = match setting {
+	    b"m" => Some(if self.inv {"0;47m".to_string()} else {"0m".to_string()}),   // any background inverts
+	    b" q" => Some(format!("{} q", match self.cursor_shape() {
+		CursorShape::Block => 1,
+		CursorShape::Underline => 3,
+		CursorShape::Bar => 5,
+	    } + if self.cursor_blinks() {0} else {1})),
+	    b"r" => Some(format!("1;{}r", self.lines.max)),
+	    b"s" => {
+		let (left, right) = self.margins().unwrap_or((0, self.config.line_width as usize));
+		Some(format!("{};{}s", left+1, right))
+	    },
+	    b"\"p" => Some("62;1\"p".to_string()),
+	    b"\"q" => Some("0\"q".to_string()),
+	    _ => None,
+	};
+	let reply = match value {
+	    Some(value) => format!("\x1bP1$r{}\x1b\\", value),
+	    None => "\x1bP0$r\x1b\\".to_string(),
+	};
+	self.reply.extend_from_slice(reply.as_bytes());
+    }
+    // at the cursor, which ends up on the line below the image
+    fn place_image(&mut self, image: Image) {
+	let (c_w, c_h) = (self.cell_size.0 as usize, self.cell_size.1 as usize);
+	let cols = cmp::min(if c_w > 0 {image.width.div_ceil(c_w)} else {1}, MAX_COLS-self.col);
+	let rows = if c_h > 0 {image.height.div_ceil(c_h)} else {1};
+	let mut used = image.rgba.len() + self.images.iter().map(|p| p.image.rgba.len()).sum::<usize>();
+	while used > self.config.image_memory && !self.images.is_empty() {   // oldest go first
+	    used -= self.images.remove(0).image.rgba.len();
+	}
+	let col = self.col;
+	self.images.push(Placement {row: self.lines.cur(), col, rows, image});
+	for i in 0..rows {
+	    if i > 0 {self.write_ascii(EscASCII::LF)};
+	    // covered cells are not blank, the lines would be hidden if empty
+	    let l = self.lines.get();
+	    if l.len() < col+cols {l.resize(col+cols, b' ')};
+	    l[col..col+cols].fill(IMAGE_CELL);
+	}
+	self.write_ascii(EscASCII::LF);
+	self.draw(None);
+    }
+    // lines moved by n, images and line sizes that left QueueView are dropped
+    fn scroll_rows(&mut self, n: isize) {
+	let len = self.lines.len();
+	let moved = |row: &mut usize| match row.checked_add_signed(n) {
+	    Some(r) if r < len => {
+		*row = r;
+		true
+	    },
+	    _ => false,
+	};
+	self.images.retain_mut(|p| moved(&mut p.row));
+	self.line_sizes.retain_mut(|(row, _)| moved(row));
+	self.wrapped.retain_mut(|row| moved(row));
+    }
+    // images, line sizes and soft wraps of cleared lines go away
+    fn retain_rows(&mut self, keep: impl Fn(usize) -> bool) {
+	self.images.retain(|p| keep(p.row));
+	self.line_sizes.retain(|(row, _)| keep(*row));
+	self.wrapped.retain(|row| keep(*row));
+    }
+    // 10: foreground, 11: background, 12: cursor
+    fn dynamic_color(&mut self, cmd: u16) -> &mut Option<color::Rgb> {
+	match cmd {
+	    10 => &mut self.colors.fg,
+	    11 => &mut self.colors.bg,
+	    _ => &mut self.colors.cursor,
+	}
+    }
+    // text written from the cursor on, clipped to the right margin
+    fn put_txt(&mut self, txt: &[u8]) {
+	let col = self.col;
+	let n = cmp::min(txt.len(), MAX_COLS-col);
+	let inv = self.inv;
+	let ambiguous_wide = self.config.ambiguous_wide;
+	let right = match self.margins() {   // text stops at the right margin when it starts inside
+	    Some((left, right)) if col >= left && col < right => right,
+	    _ => MAX_COLS,
+	};
+	let l = self.lines.get();
+	if l.len() < col { l.resize(col, b' ') };
+	self.col = if !inv && right == MAX_COLS && txt.is_ascii() {   // bulk output, copied as is
+	    let split = col > 0 && l.get(col) == Some(&WIDE_CELL);
+	    let overlap = cmp::min(n, l.len()-col);
+	    l[col..col+overlap].copy_from_slice(&txt[..overlap]);
+	    l.extend_from_slice(&txt[overlap..n]);
+	    if split {l[col-1] = b' '};   // no half wide characters left at either end
+	    if n > 0 && l.get(col+n) == Some(&WIDE_CELL) {l[col+n] = b' '};
+	    col+n
+	} else {   // non-ASCII ones shown as '?' in as many cells as they are wide
+	    let mut c = col;
+	    for ch in String::from_utf8_lossy(txt).chars() {
+		let (b, w) = match ch {
+		    ' ' if inv => (b'#', 1),
+		    ch if ch.is_ascii() => (ch as u8, 1),
+		    ch => (b'?', width::width(ch, ambiguous_wide)),
+		};
+		if w == 0 && c > 0 {   // a combining mark makes the previous cell no longer ASCII
+		    if l[c-1] != WIDE_CELL && l[c-1] != IMAGE_CELL {l[c-1] = b'?'};
+		    continue;
+		}
+		let w = cmp::max(w, 1);   // one at column 0 has nothing to attach to and takes a cell
+		if c+w > right {   // no autowrap here, the last columns are overwritten
+		    if right < w {break};
+		    c = right-w;
+		}
+		put_cell(l, c, b);
+		if w == 2 {put_cell(l, c+1, WIDE_CELL)};
+		c += w;
+	    }
+	    if right < MAX_COLS {cmp::min(c, right-1)} else {c}
+	};
+	self.draw(Some(self.lines.cur()));
+    }
+    // cells shown on a page row, double width and height rows show half as many
+    fn row_width(&self, row: usize) -> usize {
+	let width = self.config.line_width as usize;
+	if self.line_size(row).is_some() {cmp::max(width/2, 1)} else {width}
+    }
+    // byte offset in txt of the first character that no longer fits on the row with autowrap, None if all do
+    fn wrap_point(&self, txt: &[u8]) -> Option<usize> {
+	if !self.modes.autowrap || self.margins().is_some() {return None};
+	let width = self.row_width(self.lines.cur());
+	let at = |i: usize| if i > 0 || self.col > 0 {Some(i)} else {None};   // a character wider than the row stays
+	if txt.is_ascii() {
+	    let fit = width.saturating_sub(self.col);
+	    return if txt.len() > fit {at(fit)} else {None};
+	}
+	let (mut c, mut i) = (self.col, 0);
+	for chunk in txt.utf8_chunks() {
+	    let invalid = (!chunk.invalid().is_empty()).then_some((char::REPLACEMENT_CHARACTER, chunk.invalid().len()));
+	    for (ch, len) in chunk.valid().chars().map(|ch| (ch, ch.len_utf8())).chain(invalid) {
+		let w = if ch.is_ascii() {1} else {width::width(ch, self.config.ambiguous_wide)};
+		if w > 0 && c+w > width {return at(i)};
+		c += w;
+		i += len;
+	    }
+	}
+	None
+    }
+    // 0 or omitted keeps the current value
+    fn resize(&mut self, rows: Option<u16>, cols: Option<u16>) {
+	if let Some(cols) = cols.filter(|c| *c > 0) {
+	    let old = std::mem::replace(&mut self.config.line_width, cols);
+	    self.reflow(old as usize);
+	}
+	if let Some(rows) = rows.filter(|r| *r > 0) {
+	    self.config.n_lines = rows;
+	    if (rows as usize) < self.lines.len() {   // rows above the smaller page go to the scrollback, as in reflow
+		let (len, cur) = (self.lines.len(), self.lines.cur());
+		let top = cmp::min(len - rows as usize, cur);
+		let keep = cmp::min(len - top, rows as usize);
+		for row in 0..top {
+		    let line = std::mem::take(&mut self.lines[row]);
+		    self.scrollback.push(line, self.wrapped.contains(&row));
+		}
+		let mut lines = QueueView::<Vec<u8>>::new(rows);
+		for row in 0..keep {
+		    if row > 0 {lines.add(Vec::new());}
+		    lines[row] = std::mem::take(&mut self.lines[top+row]);
+		}
+		self.lines = lines;
+		self.scroll_rows(-(top as isize));
+		self.wrapped.retain(|row| row+1 < keep);
+		self.lines.end = keep;
+		self.lines.set_cur(&mut None::<fn(Option<usize>)>, cur-top);   // drawn below
+	    } else {
+		self.lines.update_max_lines(&self.config);
+	    }
+	}
+	self.draw(None);
+    }
+    fn report_scheme(&mut self) {   // 1: dark, 2: light
+	let msg = format!("\x1b[?997;{}n", if self.dark() {1} else {2});
+	self.reply.extend_from_slice(msg.as_bytes());
+    }
+    fn mode(&self, mode: u16) -> Option<bool> {
+	match mode {
+	    5 => Some(self.modes.reverse),
+	    12 => Some(self.cursor_blinks()),
+	    25 => Some(self.modes.cursor_visible),
+	    2026 => Some(self.modes.sync.is_some()),
+	    2031 => Some(self.modes.scheme_reports),
+	    2004 => Some(self.modes.bracketed_paste),
+	    7 => Some(self.modes.autowrap),
+	    69 => Some(self.modes.lr_margins),
+	    _ => None,
+	}
+    }
     fn trail(&mut self) {
 	let col = self.col;
 	let l = self.lines.get();
@@ -786,6 +1810,22 @@ mod trail {
 	assert_eq!(String::from_utf8(state.lines[0].clone()).ok().unwrap_or(String::from("invalid utf8")),"a");
     }
 }
+const KKP_STACK_SIZE: usize = 16;
+const MAX_COLS: usize = 0xffff;
+// cell covered by an image, drawn as space
+pub(super) const IMAGE_CELL: u8 = 0;
+// right half of a wide character, drawn as space
+pub(super) const WIDE_CELL: u8 = 1;
+// a cell overwritten, the other half of a wide character it splits is blanked
+fn put_cell(l: &mut Vec<u8>, c: usize, b: u8) {
+    if l.len() <= c {l.resize(c+1, b' ')};
+    if l[c] == WIDE_CELL && b != WIDE_CELL && c > 0 {
+	l[c-1] = b' ';
+    } else if l[c] != WIDE_CELL && l.get(c+1) == Some(&WIDE_CELL) {
+	l[c+1] = b' ';
+    }
+    l[c] = b;
+}
 fn one_to_zero(x: Option<u16>) -> usize {
     match x {
 	None | Some(0) => 0,